@@ -0,0 +1,452 @@
+//! A weather station simulator
+//!
+//! Emulates the station firmware on a pseudo-terminal so that station-comms
+//! can be run without any hardware. Point `serial.path` at the printed pty
+//! (or at `--link`) and set `serial.transport = "serial"`.
+//!
+//! Pseudo-terminals only exist on unix, elsewhere the simulator refuses to run.
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use clap::Parser;
+use color_eyre::{
+    eyre::{eyre, Context},
+    install, Result,
+};
+use rand::{rngs::ThreadRng, Rng};
+use scode_rs::{Code, CodeSend, ParamSend, ParamValue};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fs,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Simulate a weather station on a pseudo-terminal")]
+struct Args {
+    #[arg(long, help = "Path to a toml file describing the simulated sensors")]
+    sensors: Option<PathBuf>,
+    #[arg(long, help = "Create a symlink to the pty at this path")]
+    link: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Multiplier applied to every sensor's noise"
+    )]
+    noise: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Probability that an acknowledgement is dropped"
+    )]
+    drop_acks: f64,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Probability of garbage bytes after a response"
+    )]
+    garbage: f64,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Probability that a response is delayed"
+    )]
+    delay_chance: f64,
+    #[arg(
+        long,
+        default_value_t = 500,
+        help = "Maximum response delay in milliseconds"
+    )]
+    delay: u64,
+    #[arg(
+        long,
+        default_value_t = 1000,
+        help = "Interval between auto reports in milliseconds"
+    )]
+    auto_interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimSensor {
+    id: u8,
+    name: String,
+    unit: String,
+    value: f32,
+    #[serde(default)]
+    noise: f32,
+    min: Option<f32>,
+    max: Option<f32>,
+    #[serde(default)]
+    auto: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SensorsFile {
+    sensor: Vec<SimSensor>,
+}
+
+fn default_sensors() -> Vec<SimSensor> {
+    [
+        ("wind heading", "deg", 180.0, 20.0, Some(0.0), Some(359.9)),
+        ("wind speed", "m/s", 3.0, 1.5, Some(0.0), None),
+        ("gust 2m wind speed", "m/s", 6.0, 1.0, Some(0.0), None),
+        (
+            "gust 2m wind heading",
+            "deg",
+            190.0,
+            10.0,
+            Some(0.0),
+            Some(359.9),
+        ),
+        ("avg 2m wind speed", "m/s", 3.0, 0.3, Some(0.0), None),
+        (
+            "avg 2m wind heading",
+            "deg",
+            185.0,
+            5.0,
+            Some(0.0),
+            Some(359.9),
+        ),
+        ("avg 10m wind speed", "m/s", 3.0, 0.1, Some(0.0), None),
+        (
+            "avg 10m wind heading",
+            "deg",
+            182.0,
+            2.0,
+            Some(0.0),
+            Some(359.9),
+        ),
+        ("humidity", "%", 45.0, 1.0, Some(0.0), Some(100.0)),
+        ("temperature", "C", 21.0, 0.2, None, None),
+        ("rain hour", "mm", 0.0, 0.0, Some(0.0), None),
+        ("rain day", "mm", 0.0, 0.0, Some(0.0), None),
+        ("pressure", "hPa", 1013.0, 0.3, None, None),
+        ("uv", "index", 3.0, 0.5, Some(0.0), None),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(id, (name, unit, value, noise, min, max))| SimSensor {
+        id: id as u8,
+        name: name.into(),
+        unit: unit.into(),
+        value,
+        noise,
+        min,
+        max,
+        auto: false,
+    })
+    .collect()
+}
+
+fn encode(code: CodeSend) -> Result<Vec<u8>> {
+    Ok(Code::try_from(code)?.dump_binary_vec()?)
+}
+
+/// `O1` acknowledges a code
+fn ack_code(code: &CodeSend) -> CodeSend {
+    CodeSend {
+        letter: b'O',
+        number: 1,
+        params: vec![ParamSend {
+            letter: code.letter,
+            value: (code.number as i32).into(),
+        }],
+    }
+}
+
+struct Station {
+    args: Args,
+    sensors: Vec<SimSensor>,
+    autos: BTreeSet<u8>,
+    rng: ThreadRng,
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    next_auto: Instant,
+}
+
+impl Station {
+    fn new(args: Args, sensors: Vec<SimSensor>) -> Self {
+        let autos = sensors.iter().filter(|s| s.auto).map(|s| s.id).collect();
+        Self {
+            args,
+            sensors,
+            autos,
+            rng: rand::thread_rng(),
+            queue: VecDeque::new(),
+            next_auto: Instant::now(),
+        }
+    }
+
+    fn sample(&mut self, id: u8) -> Option<f32> {
+        let scale = self.args.noise;
+        let sensor = self.sensors.iter().find(|s| s.id == id)?;
+        let noise = sensor.noise * scale;
+        let mut value = if noise > 0.0 {
+            sensor.value + self.rng.gen_range(-noise..=noise)
+        } else {
+            sensor.value
+        };
+        if let Some(min) = sensor.min {
+            value = value.max(min);
+        }
+        if let Some(max) = sensor.max {
+            value = value.min(max);
+        }
+        Some(value)
+    }
+
+    fn sensor_code(&mut self, id: u8, only_value: bool) -> Option<CodeSend> {
+        let value = self.sample(id)?;
+        let sensor = self.sensors.iter().find(|s| s.id == id)?;
+        let mut params = Vec::new();
+        if !only_value {
+            params.push(ParamSend {
+                letter: b'N',
+                value: ParamValue::str(sensor.name.as_str()),
+            });
+            params.push(ParamSend {
+                letter: b'U',
+                value: ParamValue::str(sensor.unit.as_str()),
+            });
+        }
+        params.push(ParamSend {
+            letter: b'V',
+            value: ParamValue::str(format!("{value:.2}").as_str()),
+        });
+        Some(CodeSend {
+            letter: b'S',
+            number: id,
+            params,
+        })
+    }
+
+    fn autos_code(&self) -> CodeSend {
+        CodeSend {
+            letter: b'M',
+            number: 102,
+            params: self
+                .autos
+                .iter()
+                .map(|id| ParamSend {
+                    letter: b'E',
+                    value: (*id as i32).into(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Queue a response, applying any configured failures
+    fn respond(&mut self, replies: Vec<CodeSend>, ack: Option<CodeSend>) -> Result<()> {
+        let mut bytes = Vec::new();
+        for reply in replies {
+            bytes.append(&mut encode(reply)?);
+        }
+        if let Some(ack) = ack {
+            if self.rng.gen_bool(self.args.drop_acks) {
+                println!("dropping ack {}{}", ack.letter as char, ack.number);
+            } else {
+                bytes.append(&mut encode(ack)?);
+            }
+        }
+        if self.rng.gen_bool(self.args.garbage) {
+            let len = self.rng.gen_range(1..16);
+            bytes.extend((0..len).map(|_| self.rng.gen::<u8>()));
+        }
+
+        let mut due = Instant::now();
+        if self.args.delay > 0 && self.rng.gen_bool(self.args.delay_chance) {
+            due += Duration::from_millis(self.rng.gen_range(0..=self.args.delay));
+        }
+        // The firmware answers in order, so a delayed response holds up the rest
+        if let Some((last, _)) = self.queue.back() {
+            due = due.max(*last);
+        }
+        self.queue.push_back((due, bytes));
+        Ok(())
+    }
+
+    fn handle(&mut self, code: CodeSend) -> Result<()> {
+        let ack = Some(ack_code(&code));
+        match (code.letter, code.number) {
+            (b'M', 1) => {
+                let ids: Vec<u8> = self.sensors.iter().map(|s| s.id).collect();
+                let replies = ids
+                    .into_iter()
+                    .filter_map(|id| self.sensor_code(id, false))
+                    .collect();
+                self.respond(replies, ack)
+            }
+            (b'S', id) => {
+                let only_value = code.find(b'R').is_some();
+                match self.sensor_code(id, only_value) {
+                    Some(reply) => self.respond(vec![reply], ack),
+                    None => {
+                        // The firmware has no way to refuse, it just never answers
+                        println!("no such sensor {id}");
+                        Ok(())
+                    }
+                }
+            }
+            (b'M', 102) => {
+                for p in &code.params {
+                    let value = p.value.as_borrowed();
+                    match p.letter {
+                        b'E' => {
+                            if let Ok(id) = value.cast_u8() {
+                                self.autos.insert(id);
+                            }
+                        }
+                        b'D' => {
+                            if let Ok(id) = value.cast_u8() {
+                                self.autos.remove(&id);
+                            }
+                        }
+                        b'V' => {
+                            if let Ok(mask) = value.cast_i64() {
+                                self.autos = (0..64).filter(|i| mask & (1 << i) != 0).collect();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let reply = self.autos_code();
+                self.respond(vec![reply], ack)
+            }
+            (b'M', 10) => {
+                let days = code
+                    .find(b'D')
+                    .and_then(|p| p.value.as_borrowed().cast_i64().ok());
+                let ms = code
+                    .find(b'T')
+                    .and_then(|p| p.value.as_borrowed().cast_i64().ok());
+                if let (Some(days), Some(ms)) = (days, ms) {
+                    println!("clock set to day {days} at {ms}ms");
+                }
+                self.respond(vec![], ack)
+            }
+            (b'M', 20) => {
+                println!("resetting");
+                self.autos = self
+                    .sensors
+                    .iter()
+                    .filter(|s| s.auto)
+                    .map(|s| s.id)
+                    .collect();
+                self.respond(vec![], ack)
+            }
+            (letter, number) => {
+                println!("ignoring unknown code {}{number}", letter as char);
+                Ok(())
+            }
+        }
+    }
+
+    /// Push any auto reported sensors that are due
+    fn auto_report(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if self.autos.is_empty() || now < self.next_auto {
+            return Ok(());
+        }
+        self.next_auto = now + Duration::from_millis(self.args.auto_interval);
+        let ids: Vec<u8> = self.autos.iter().copied().collect();
+        let replies = ids
+            .into_iter()
+            .filter_map(|id| self.sensor_code(id, true))
+            .collect();
+        self.respond(replies, None)
+    }
+
+    fn flush(&mut self, port: &mut impl Write) -> Result<()> {
+        let now = Instant::now();
+        while let Some((due, _)) = self.queue.front() {
+            if *due > now {
+                break;
+            }
+            let (_, bytes) = self.queue.pop_front().unwrap();
+            port.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    install()?;
+
+    let args = Args::parse();
+    for (name, p) in [
+        ("drop-acks", args.drop_acks),
+        ("garbage", args.garbage),
+        ("delay-chance", args.delay_chance),
+    ] {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(eyre!("--{name} must be between 0 and 1"));
+        }
+    }
+
+    let sensors = match &args.sensors {
+        Some(path) => {
+            let contents =
+                fs::read_to_string(path).with_context(|| format!("Could not open {path:?}"))?;
+            toml::from_str::<SensorsFile>(&contents)?.sensor
+        }
+        None => default_sensors(),
+    };
+    simulate(args, sensors)
+}
+
+/// Run the station on a new pseudo-terminal until killed
+#[cfg(unix)]
+fn simulate(args: Args, sensors: Vec<SimSensor>) -> Result<()> {
+    use scode_rs::CodeStream;
+    use serialport::{SerialPort, TTYPort};
+    use std::{
+        io::{self, Read},
+        thread,
+    };
+
+    let (mut master, mut slave) = TTYPort::pair()?;
+    // station-comms needs to be able to open the pty while we hold it
+    slave.set_exclusive(false)?;
+    let name = slave
+        .name()
+        .ok_or_else(|| eyre!("Could not determine the name of the pty"))?;
+    println!("simulating a station on {name}");
+    if let Some(link) = &args.link {
+        let _ = fs::remove_file(link);
+        std::os::unix::fs::symlink(&name, link)
+            .with_context(|| format!("Could not link {link:?} to {name}"))?;
+        println!("linked {link:?} to {name}");
+    }
+
+    master.set_timeout(Duration::from_millis(20))?;
+    let mut station = Station::new(args, sensors);
+    let mut stream = CodeStream::with_capacity(64);
+    let mut buf = [0; 64];
+
+    loop {
+        match master.read(&mut buf) {
+            Ok(len) => {
+                stream.extend(&buf[0..len]);
+                for code in &mut stream {
+                    match code {
+                        Ok(code) => station.handle(CodeSend::from(code))?,
+                        Err(err) => eprintln!("{err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => {
+                eprintln!("{err}");
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        station.auto_report()?;
+        station.flush(&mut master)?;
+    }
+}
+
+#[cfg(not(unix))]
+fn simulate(_args: Args, _sensors: Vec<SimSensor>) -> Result<()> {
+    Err(eyre!("the simulator needs a unix pseudo-terminal"))
+}
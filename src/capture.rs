@@ -0,0 +1,312 @@
+use color_eyre::{eyre::eyre, Result};
+use ordoo::or_do;
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::transport::Transport;
+
+/// Direction of a captured chunk of bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+impl Direction {
+    fn tag(self) -> char {
+        match self {
+            Self::Read => 'R',
+            Self::Write => 'W',
+        }
+    }
+}
+
+/// A transport that records all traffic of another transport to a file
+///
+/// Each line of the capture is `<ms since start> <R|W> <hex bytes>`.
+pub struct CaptureTransport {
+    inner: Box<dyn Transport>,
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl CaptureTransport {
+    /// Start a new capture in `dir` named after the current time
+    pub fn create(dir: impl AsRef<Path>, inner: Box<dyn Transport>) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let now = chrono::Local::now();
+        let path = dir.join(format!("capture-{}.log", now.format("%Y%m%dT%H%M%S%.3f")));
+        let mut file = BufWriter::new(File::create(&path)?);
+        writeln!(file, "# station-comms capture {}", now.to_rfc3339())?;
+        file.flush()?;
+        println!("capturing serial traffic to {path:?}");
+        Ok(Self {
+            inner,
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let mut hex = String::with_capacity(bytes.len() * 2);
+        for b in bytes {
+            write!(hex, "{b:02x}")?;
+        }
+        writeln!(
+            self.file,
+            "{} {} {hex}",
+            self.start.elapsed().as_millis(),
+            direction.tag()
+        )?;
+        // Flush every record so a crash still leaves a usable capture
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.record(Direction::Read, &buf[0..len])?;
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.record(Direction::Write, &buf[0..len])?;
+        Ok(len)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+}
+
+/// A transport that plays back the station's side of a capture
+///
+/// Anything written to the transport is discarded.
+pub struct ReplayTransport {
+    records: Vec<(Duration, Vec<u8>)>,
+    next: usize,
+    pending: Vec<u8>,
+    speed: f32,
+    timeout: Duration,
+    start: Option<Instant>,
+}
+
+impl ReplayTransport {
+    /// Load a capture
+    ///
+    /// `speed` scales the original timing, where 2.0 replays twice as fast.
+    /// A speed of 0 replays everything as fast as possible.
+    pub fn open(path: impl AsRef<Path>, speed: f32) -> Result<Self> {
+        if !speed.is_finite() || speed < 0.0 {
+            return Err(eyre!("replay speed must be finite and not negative"));
+        }
+        let contents = fs::read_to_string(path)?;
+        let mut records = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let ((time, direction), hex) = or_do!(
+                parts.next().zip(parts.next()).zip(parts.next()),
+                return Err(eyre!("line {}: malformed capture record", i + 1))
+            );
+            if direction != "R" {
+                continue;
+            }
+            let time: u64 = time
+                .parse()
+                .map_err(|e| eyre!("line {}: bad timestamp: {e}", i + 1))?;
+            let bytes = or_do!(
+                decode_hex(hex),
+                return Err(eyre!("line {}: bad hex data", i + 1))
+            );
+            records.push((Duration::from_millis(time), bytes));
+        }
+        Ok(Self {
+            records,
+            next: 0,
+            pending: Vec::new(),
+            speed,
+            timeout: Duration::ZERO,
+            start: None,
+        })
+    }
+
+    fn due(&self, offset: Duration) -> Duration {
+        if self.speed == 0.0 {
+            Duration::ZERO
+        } else {
+            offset.div_f32(self.speed)
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Transport for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        if self.pending.is_empty() {
+            let (offset, _) = or_do!(self.records.get(self.next), {
+                if self.next == self.records.len() {
+                    println!("replay finished");
+                    self.next += 1;
+                }
+                thread::sleep(self.timeout);
+                return Ok(0);
+            });
+            let due = start + self.due(*offset);
+            let now = Instant::now();
+            if due > now {
+                let wait = due - now;
+                if wait > self.timeout {
+                    thread::sleep(self.timeout);
+                    return Ok(0);
+                }
+                thread::sleep(wait);
+            }
+            self.pending = std::mem::take(&mut self.records[self.next].1);
+            self.next += 1;
+        }
+        let len = self.pending.len().min(buf.len());
+        buf[0..len].copy_from_slice(&self.pending[0..len]);
+        self.pending.drain(0..len);
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scode_rs::{CodeSend, CodeStream};
+    use std::{
+        collections::HashMap,
+        sync::{mpsc, Arc, Mutex},
+    };
+
+    use crate::{
+        history::Histories,
+        protocol::{
+            tests::{ack, encode, report, value_report},
+            StationCommand,
+        },
+        sensor::{SensorEvent, Sensors},
+        station::CodeHandler,
+        transport::MemoryTransport,
+    };
+
+    /// Read until the transport has nothing more to give
+    fn read_all(transport: &mut dyn Transport) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            let len = transport.read(&mut buf).unwrap();
+            if len == 0 {
+                return bytes;
+            }
+            bytes.extend_from_slice(&buf[0..len]);
+        }
+    }
+
+    /// Record a short session with a station, returning the capture's path
+    fn record(dir: &Path) -> std::path::PathBuf {
+        let (near, mut station) = MemoryTransport::pair();
+        let mut capture = CaptureTransport::create(dir, Box::new(near)).unwrap();
+        capture.set_timeout(Duration::from_millis(50)).unwrap();
+
+        let command = CodeSend::from(StationCommand::RequestAllSensors);
+        capture.write(&encode([command])).unwrap();
+        station
+            .write(&encode([
+                report(0, "temp", "C", 21.5),
+                report(1, "humidity", "%", 40.0),
+                ack(b'M', 1),
+            ]))
+            .unwrap();
+        station.write(&encode([value_report(0, 22.0)])).unwrap();
+        read_all(&mut capture);
+
+        let mut captures = fs::read_dir(dir).unwrap();
+        let path = captures.next().unwrap().unwrap().path();
+        assert!(captures.next().is_none());
+        path
+    }
+
+    #[test]
+    fn replay_into_sensors() {
+        let dir =
+            std::env::temp_dir().join(format!("station-comms-capture-{}", std::process::id()));
+        let path = record(&dir);
+        let mut replay = ReplayTransport::open(&path, 0.0).unwrap();
+        let bytes = read_all(&mut replay);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let sensors = Arc::new(Mutex::new(Sensors::new(
+            HashMap::new(),
+            Histories::new(Duration::from_secs(60), 16),
+        )));
+        let (events_tx, events) = mpsc::channel::<SensorEvent>();
+        let mut handler = CodeHandler::new();
+        handler.callback(Sensors::sensor_callback(&sensors, events_tx));
+        let mut stream = CodeStream::with_capacity(64);
+        stream.extend(&bytes);
+        // Only the station's side is replayed, so the M1 that was sent is skipped
+        let mut letters = Vec::new();
+        for code in &mut stream {
+            let code = CodeSend::from(code.unwrap_or_else(|err| panic!("{err}")));
+            letters.push(code.letter);
+            handler.code(code);
+        }
+        assert_eq!(letters, b"SSOS");
+
+        let sensors = sensors.lock().unwrap();
+        let temp = sensors.get("temp").unwrap();
+        assert_eq!((temp.id, &*temp.unit, temp.value), (0, "C", 22.0));
+        let humidity = sensors.get("humidity").unwrap();
+        assert_eq!(
+            (humidity.id, &*humidity.unit, humidity.value),
+            (1, "%", 40.0)
+        );
+        let added = events
+            .try_iter()
+            .filter(|e| matches!(e, SensorEvent::Added { .. }))
+            .count();
+        assert_eq!(added, 2);
+    }
+}
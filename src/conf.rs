@@ -1,17 +1,27 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use color_eyre::Result;
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use color_eyre::{eyre::eyre, Result};
 use serde::Deserialize;
 use toml;
 
+use crate::units::UnitSystem;
+
 #[derive(Debug, Deserialize)]
 pub struct MqttConf {
     pub host: String,
     pub timeout: Option<f32>,
     pub id: String,
+    /// Units of the weather updates
+    #[serde(default)]
+    pub units: UnitSystem,
+    /// Units of the rapid weather updates, the same as `units` if not set
+    pub rapid_units: Option<UnitSystem>,
 }
 
 const DATABITS_DEFAULT: u8 = 8;
@@ -24,7 +34,7 @@ fn stopbits_default() -> u8 {
     STOPBITS_DEFAULT
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub enum Parity {
     None,
     Even,
@@ -33,6 +43,7 @@ pub enum Parity {
     Space,
 }
 
+#[cfg(feature = "uart")]
 impl From<Parity> for rppal::uart::Parity {
     fn from(value: Parity) -> Self {
         match value {
@@ -50,9 +61,32 @@ impl Default for Parity {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// How the station is connected
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Raspberry Pi UART through rppal
+    Uart,
+    /// Any POSIX serial device or pseudo-terminal
+    Serial,
+    /// A raw TCP socket, such as a serial-to-network bridge
+    Tcp,
+}
+
+impl Default for TransportKind {
+    fn default() -> Self {
+        Self::Uart
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct SerialConf {
+    #[serde(default)]
+    pub transport: TransportKind,
+    #[serde(default)]
     pub path: PathBuf,
+    /// Address of the station when using the tcp transport
+    pub address: Option<String>,
     pub baudrate: u32,
     #[serde(default)]
     pub parity: Parity,
@@ -62,6 +96,510 @@ pub struct SerialConf {
     pub stopbits: u8,
 }
 
+const AUTO_TIMEOUT_DEFAULT: f32 = 10.0;
+const DISCOVERY_INTERVAL_DEFAULT: f32 = 600.0;
+
+fn auto_timeout_default() -> f32 {
+    AUTO_TIMEOUT_DEFAULT
+}
+fn discovery_interval_default() -> f32 {
+    DISCOVERY_INTERVAL_DEFAULT
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollingConf {
+    /// Seconds without a push from an auto reported sensor before it is
+    /// polled again
+    #[serde(default = "auto_timeout_default")]
+    pub auto_timeout: f32,
+    /// Seconds between asking the station for its full list of sensors
+    #[serde(default = "discovery_interval_default")]
+    pub discovery_interval: f32,
+}
+
+impl Default for PollingConf {
+    fn default() -> Self {
+        Self {
+            auto_timeout: AUTO_TIMEOUT_DEFAULT,
+            discovery_interval: DISCOVERY_INTERVAL_DEFAULT,
+        }
+    }
+}
+
+/// What to publish for a reading that is not fresh
+///
+/// Applies to out of range readings just as it does to stale ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePolicy {
+    /// Publish the last known value, flagged with its quality
+    Keep,
+    /// Publish the quality with a null value
+    Null,
+    /// Leave the reading out of the update
+    Omit,
+}
+
+impl Default for StalePolicy {
+    fn default() -> Self {
+        Self::Keep
+    }
+}
+
+/// Overrides for a single sensor, by name
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SensorLimits {
+    /// Seconds before a reading is stale
+    pub max_age: Option<f32>,
+    /// Readings below this are out of range
+    pub min: Option<f32>,
+    /// Readings above this are out of range
+    pub max: Option<f32>,
+}
+
+const MAX_AGE_DEFAULT: f32 = 300.0;
+
+fn max_age_default() -> f32 {
+    MAX_AGE_DEFAULT
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QualityConf {
+    /// Seconds before a reading is stale
+    #[serde(default = "max_age_default")]
+    pub max_age: f32,
+    #[serde(default)]
+    pub stale: StalePolicy,
+    /// Publish a reading with the `missing` quality for keys that have none
+    #[serde(default)]
+    pub missing: bool,
+    #[serde(default)]
+    pub sensors: HashMap<String, SensorLimits>,
+}
+
+impl Default for QualityConf {
+    fn default() -> Self {
+        Self {
+            max_age: MAX_AGE_DEFAULT,
+            stale: StalePolicy::default(),
+            missing: false,
+            sensors: HashMap::new(),
+        }
+    }
+}
+
+impl QualityConf {
+    /// Whether a value is within the limits of a sensor
+    pub fn in_range(&self, name: &str, value: f32) -> bool {
+        let limits = self.sensors.get(name);
+        let min = limits.and_then(|l| l.min).unwrap_or(f32::NEG_INFINITY);
+        let max = limits.and_then(|l| l.max).unwrap_or(f32::INFINITY);
+        (min..=max).contains(&value)
+    }
+}
+
+/// The station sensors published under a key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SensorMapping {
+    /// `false` stops publishing a default key, `true` keeps its default
+    Enabled(bool),
+    /// A single station sensor
+    One(String),
+    /// Every one of these station sensors
+    Many(Vec<String>),
+    Table {
+        sensors: Vec<String>,
+        /// Other names for the sensors, used when none of `sensors` exist
+        #[serde(default)]
+        aliases: Vec<String>,
+    },
+}
+
+impl SensorMapping {
+    /// The sensors and their aliases, both empty when disabled
+    pub fn names(&self) -> (&[String], &[String]) {
+        match self {
+            Self::Enabled(_) => (&[], &[]),
+            Self::One(name) => (std::slice::from_ref(name), &[]),
+            Self::Many(names) => (names, &[]),
+            Self::Table { sensors, aliases } => (sensors, aliases),
+        }
+    }
+}
+
+fn default_mapping() -> HashMap<String, SensorMapping> {
+    [
+        ("winddir", "wind heading"),
+        ("windspd", "wind speed"),
+        ("windgustspd-2m", "gust 2m wind speed"),
+        ("windgustdir-2m", "gust 2m wind heading"),
+        ("windspd-avg2m", "avg 2m wind speed"),
+        ("winddir-avg2m", "avg 2m wind heading"),
+        ("windspd-avg10m", "avg 10m wind speed"),
+        ("winddir-avg10m", "avg 10m wind heading"),
+        ("humidity", "humidity"),
+        ("temp", "temperature"),
+        ("rain-1h", "rain hour"),
+        ("dailyrain", "rain day"),
+        ("barom", "pressure"),
+        ("uv", "uv"),
+    ]
+    .into_iter()
+    .map(|(key, name)| (key.into(), SensorMapping::One(name.into())))
+    .collect()
+}
+
+/// Which station sensors are published under which keys
+///
+/// Keys in `station.toml` are merged over the defaults.
+#[derive(Debug, Deserialize)]
+pub struct SensorsConf {
+    /// Publish sensors that no key mentions under their own name
+    #[serde(default)]
+    pub passthrough: bool,
+    #[serde(flatten)]
+    pub keys: HashMap<String, SensorMapping>,
+    /// Every sensor mentioned by a key, including disabled defaults
+    #[serde(skip)]
+    mapped: HashSet<String>,
+}
+
+impl SensorsConf {
+    fn merge_defaults(&mut self) {
+        let mut mapped = HashSet::new();
+        for (key, default) in default_mapping() {
+            // Disabled keys still claim their sensors, so passthrough won't publish them
+            mapped.extend(default.names().0.iter().cloned());
+            if let None | Some(SensorMapping::Enabled(true)) = self.keys.get(&key) {
+                self.keys.insert(key, default);
+            }
+        }
+        for mapping in self.keys.values() {
+            let (sensors, aliases) = mapping.names();
+            mapped.extend(sensors.iter().chain(aliases).cloned());
+        }
+        self.mapped = mapped;
+    }
+
+    /// Whether any key mentions the sensor
+    pub fn is_mapped(&self, name: &str) -> bool {
+        self.mapped.contains(name)
+    }
+}
+
+impl Default for SensorsConf {
+    fn default() -> Self {
+        let mut conf = Self {
+            passthrough: false,
+            keys: HashMap::new(),
+            mapped: HashSet::new(),
+        };
+        conf.merge_defaults();
+        conf
+    }
+}
+
+/// Corrections for a drifting sensor
+///
+/// The raw value first goes through the `polynomial` or `table` curve, then
+/// gets scaled and offset.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    pub offset: f32,
+    #[serde(default = "scale_default")]
+    pub scale: f32,
+    /// Coefficients from the constant term up, `c0 + c1*x + c2*x^2 ...`
+    pub polynomial: Option<Vec<f32>>,
+    /// `[raw, actual]` points, linearly interpolated between
+    pub table: Option<Vec<[f32; 2]>>,
+}
+
+fn scale_default() -> f32 {
+    1.0
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: f32) -> f32 {
+        let curved = if let Some(coefficients) = &self.polynomial {
+            coefficients.iter().rev().fold(0.0, |acc, c| acc * raw + c)
+        } else if let Some(table) = &self.table {
+            interpolate(table, raw)
+        } else {
+            raw
+        };
+        curved * self.scale + self.offset
+    }
+}
+
+/// Linear interpolation through sorted points, extending the outermost segments
+fn interpolate(table: &[[f32; 2]], x: f32) -> f32 {
+    match table {
+        [] => x,
+        [[x0, y0]] => x - x0 + y0,
+        _ => {
+            let i = table[1..table.len() - 1]
+                .iter()
+                .take_while(|[px, _]| *px < x)
+                .count();
+            let ([x0, y0], [x1, y1]) = (table[i], table[i + 1]);
+            if x1 == x0 {
+                return y0;
+            }
+            y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+        }
+    }
+}
+
+const HISTORY_WINDOW_DEFAULT: f32 = 24.0 * 60.0 * 60.0;
+const HISTORY_SAMPLES_DEFAULT: usize = 86400;
+
+fn history_window_default() -> f32 {
+    HISTORY_WINDOW_DEFAULT
+}
+fn history_samples_default() -> usize {
+    HISTORY_SAMPLES_DEFAULT
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryConf {
+    /// Seconds of history to keep for each sensor
+    #[serde(default = "history_window_default")]
+    pub window: f32,
+    /// The most samples to keep for each sensor
+    #[serde(default = "history_samples_default")]
+    pub max_samples: usize,
+}
+
+impl Default for HistoryConf {
+    fn default() -> Self {
+        Self {
+            window: HISTORY_WINDOW_DEFAULT,
+            max_samples: HISTORY_SAMPLES_DEFAULT,
+        }
+    }
+}
+
+fn true_default() -> bool {
+    true
+}
+
+/// Which derived quantities to publish
+#[derive(Debug, Deserialize)]
+pub struct DerivedConf {
+    #[serde(default = "true_default")]
+    pub dewpoint: bool,
+    #[serde(default)]
+    pub heat_index: bool,
+    #[serde(default)]
+    pub wind_chill: bool,
+    #[serde(default)]
+    pub feels_like: bool,
+    #[serde(default)]
+    pub humidex: bool,
+    #[serde(default)]
+    pub absolute_humidity: bool,
+    #[serde(default)]
+    pub vapor_pressure: bool,
+    #[serde(default)]
+    pub wet_bulb: bool,
+    #[serde(default)]
+    pub cloud_base: bool,
+    /// Sea-level pressure through the current temperature
+    #[serde(default = "true_default")]
+    pub qff: bool,
+    /// Sea-level pressure through the standard atmosphere
+    #[serde(default = "true_default")]
+    pub qnh: bool,
+    /// The altimeter setting
+    #[serde(default = "true_default")]
+    pub altimeter: bool,
+    /// The 3 hour pressure tendency
+    #[serde(default = "true_default")]
+    pub tendency: bool,
+    /// A Zambretti forecast
+    #[serde(default = "true_default")]
+    pub forecast: bool,
+}
+
+impl Default for DerivedConf {
+    fn default() -> Self {
+        Self {
+            dewpoint: true,
+            heat_index: false,
+            wind_chill: false,
+            feels_like: false,
+            humidex: false,
+            absolute_humidity: false,
+            vapor_pressure: false,
+            wet_bulb: false,
+            cloud_base: false,
+            qff: true,
+            qnh: true,
+            altimeter: true,
+            tendency: true,
+            forecast: true,
+        }
+    }
+}
+
+fn wind_windows_default() -> Vec<u32> {
+    vec![2, 10]
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WindConf {
+    /// Fill in the wind averages the station doesn't have sensors for
+    #[serde(default = "true_default")]
+    pub enabled: bool,
+    /// Minutes to average the wind over
+    #[serde(default = "wind_windows_default")]
+    pub windows: Vec<u32>,
+}
+
+impl Default for WindConf {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            windows: wind_windows_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtremesConf {
+    /// Track and publish the extremes of each day
+    #[serde(default = "true_default")]
+    pub enabled: bool,
+}
+
+impl Default for ExtremesConf {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// The timezone that days are counted in
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TimeZone {
+    /// The system's timezone
+    Local,
+    Utc,
+    /// A fixed offset from UTC, such as `-07:00`
+    Fixed(FixedOffset),
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::Local
+    }
+}
+
+impl TryFrom<String> for TimeZone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "local" => return Ok(Self::Local),
+            "utc" | "z" => return Ok(Self::Utc),
+            _ => {}
+        }
+        let invalid = || format!("invalid timezone {value:?}, expected local, utc or +HH:MM");
+        let (sign, offset) = if let Some(offset) = value.strip_prefix('+') {
+            (1, offset)
+        } else if let Some(offset) = value.strip_prefix('-') {
+            (-1, offset)
+        } else {
+            return Err(invalid());
+        };
+        let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Self::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+/// When a weather day starts
+#[derive(Debug, Default, Deserialize)]
+pub struct DayConf {
+    /// Hour of the day that daily totals and extremes reset at
+    #[serde(default)]
+    pub reset_hour: u32,
+    #[serde(default)]
+    pub timezone: TimeZone,
+}
+
+impl DayConf {
+    /// The weather day that `time` falls in
+    ///
+    /// Days are named after the date they start on.
+    pub fn date(&self, time: DateTime<Utc>) -> NaiveDate {
+        let local = match self.timezone {
+            TimeZone::Local => time.with_timezone(&chrono::Local).naive_local(),
+            TimeZone::Utc => time.naive_utc(),
+            TimeZone::Fixed(offset) => time.with_timezone(&offset).naive_local(),
+        };
+        (local - chrono::Duration::hours(self.reset_hour as i64)).date()
+    }
+
+    /// `time` as an RFC 3339 timestamp in the configured timezone
+    pub fn rfc3339(&self, time: DateTime<Utc>) -> String {
+        match self.timezone {
+            TimeZone::Local => time.with_timezone(&chrono::Local).to_rfc3339(),
+            TimeZone::Utc => time.to_rfc3339(),
+            TimeZone::Fixed(offset) => time.with_timezone(&offset).to_rfc3339(),
+        }
+    }
+}
+
+const RAIN_RATE_WINDOW_DEFAULT: f32 = 15.0;
+const RAIN_STORM_GAP_DEFAULT: f32 = 24.0;
+
+fn rain_counter_default() -> String {
+    "dailyrain".into()
+}
+fn rain_rate_window_default() -> f32 {
+    RAIN_RATE_WINDOW_DEFAULT
+}
+fn rain_storm_gap_default() -> f32 {
+    RAIN_STORM_GAP_DEFAULT
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RainConf {
+    /// Keep rain totals on the host
+    #[serde(default = "true_default")]
+    pub enabled: bool,
+    /// Key of the station's rain counter
+    #[serde(default = "rain_counter_default")]
+    pub counter: String,
+    /// Minutes of rain that the rate is measured over
+    #[serde(default = "rain_rate_window_default")]
+    pub rate_window: f32,
+    /// Hours without rain that end a storm
+    #[serde(default = "rain_storm_gap_default")]
+    pub storm_gap: f32,
+}
+
+impl Default for RainConf {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            counter: rain_counter_default(),
+            rate_window: RAIN_RATE_WINDOW_DEFAULT,
+            storm_gap: RAIN_STORM_GAP_DEFAULT,
+        }
+    }
+}
+
+fn state_dir_default() -> PathBuf {
+    "/var/lib/station-comms".into()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Conf {
     pub make: String,
@@ -72,15 +610,90 @@ pub struct Conf {
     pub country: String,
     pub latitude: f64,
     pub longitude: f64,
+    /// Meters above sea level of the pressure sensor
     pub elevation: f64,
     pub mqtt: MqttConf,
     pub serial: SerialConf,
+    #[serde(default)]
+    pub polling: PollingConf,
+    #[serde(default)]
+    pub quality: QualityConf,
+    #[serde(default)]
+    pub sensors: SensorsConf,
+    /// Calibrations by sensor name
+    #[serde(default)]
+    pub calibration: HashMap<String, Calibration>,
+    #[serde(default)]
+    pub derived: DerivedConf,
+    #[serde(default)]
+    pub history: HistoryConf,
+    #[serde(default)]
+    pub wind: WindConf,
+    #[serde(default)]
+    pub day: DayConf,
+    #[serde(default)]
+    pub rain: RainConf,
+    #[serde(default)]
+    pub extremes: ExtremesConf,
+    /// Where to keep state that has to survive a restart
+    #[serde(default = "state_dir_default")]
+    pub state_dir: PathBuf,
 }
 
 impl Conf {
     pub fn load(path: impl AsRef<Path>) -> Result<Conf> {
         let contents = fs::read_to_string(path)?;
-        let conf: Conf = toml::from_str(&contents)?;
+        let mut conf: Conf = toml::from_str(&contents)?;
+        conf.sensors.merge_defaults();
+        // Every default has been filled in, so whatever is left has no default to keep
+        let enabled = conf
+            .sensors
+            .keys
+            .iter()
+            .find(|(_, mapping)| matches!(mapping, SensorMapping::Enabled(true)));
+        if let Some((key, _)) = enabled {
+            return Err(eyre!(
+                "sensors.{key} has no default to enable, map it to a sensor instead"
+            ));
+        }
+        let mut durations = vec![
+            ("history.window".to_owned(), conf.history.window),
+            ("polling.auto_timeout".to_owned(), conf.polling.auto_timeout),
+            (
+                "polling.discovery_interval".to_owned(),
+                conf.polling.discovery_interval,
+            ),
+            ("quality.max_age".to_owned(), conf.quality.max_age),
+            ("rain.rate_window".to_owned(), conf.rain.rate_window * 60.0),
+            ("rain.storm_gap".to_owned(), conf.rain.storm_gap * 3600.0),
+        ];
+        durations.extend(conf.mqtt.timeout.map(|t| ("mqtt.timeout".to_owned(), t)));
+        for (name, limits) in &conf.quality.sensors {
+            if let Some(max_age) = limits.max_age {
+                durations.push((format!("quality.sensors.{name:?}.max_age"), max_age));
+            }
+        }
+        for (name, secs) in durations {
+            if Duration::try_from_secs_f32(secs).is_err() {
+                return Err(eyre!("{name} must be a finite, non-negative duration"));
+            }
+        }
+        if conf.rain.rate_window <= 0.0 {
+            return Err(eyre!("rain.rate_window must be longer than 0 minutes"));
+        }
+        if conf.day.reset_hour >= 24 {
+            return Err(eyre!("day.reset_hour must be less than 24"));
+        }
+        for (name, calibration) in conf.calibration.iter_mut() {
+            if calibration.polynomial.is_some() && calibration.table.is_some() {
+                return Err(eyre!(
+                    "calibration for {name:?} can't have both a polynomial and a table"
+                ));
+            }
+            if let Some(table) = &mut calibration.table {
+                table.sort_by(|a, b| a[0].total_cmp(&b[0]));
+            }
+        }
         Ok(conf)
     }
 }
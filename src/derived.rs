@@ -0,0 +1,326 @@
+use std::time::Instant;
+
+use crate::{
+    conf::{DerivedConf, QualityConf, SensorsConf},
+    report::{self, ReportBuilder},
+    sensor::{Quality, Sensors},
+    units::Unit,
+};
+
+/// Magnus coefficients over water
+const MAGNUS_A: f32 = 17.625;
+const MAGNUS_B: f32 = 243.04;
+
+/// A sensor reading in the base unit of its quantity
+#[derive(Debug, Clone, Copy)]
+pub struct Input {
+    pub value: f32,
+    /// The unit the station reported in
+    pub unit: Option<Unit>,
+    pub measured: Instant,
+    pub quality: Quality,
+}
+
+impl Input {
+    /// The first sensor behind `key`, converted to `base`
+    ///
+    /// Sensors in a unit of another quantity are ignored. Without a `base`
+    /// the value is used as is.
+    fn lookup(
+        sensors: &Sensors,
+        conf: &SensorsConf,
+        quality: &QualityConf,
+        key: &str,
+        base: Option<Unit>,
+    ) -> Option<Self> {
+        let sensor = report::lookup(sensors, conf, key).into_iter().next()?;
+        let unit = Unit::parse(&sensor.unit);
+        let value = match base {
+            Some(base) => unit?.convert(sensor.value, base)?,
+            None => sensor.value,
+        };
+        Some(Self {
+            value,
+            unit,
+            measured: sensor.last_update,
+            quality: sensor.quality(quality),
+        })
+    }
+
+    /// A value computed from this and `other`
+    ///
+    /// It is as old and as bad as the worst of the two.
+    fn and(self, other: Input) -> Self {
+        Self {
+            value: self.value,
+            unit: self.unit,
+            measured: self.measured.min(other.measured),
+            quality: self.quality.max(other.quality),
+        }
+    }
+}
+
+/// The readings that derived quantities are computed from
+///
+/// These are looked up through the `temp`, `humidity`, `windspd` and `barom`
+/// keys, so remapping one of those keys changes the input as well, and
+/// disabling one leaves everything derived from it missing.
+pub struct Inputs {
+    /// Temperature in C
+    pub temp: Option<Input>,
+    /// Relative humidity in %
+    pub humidity: Option<Input>,
+    /// Wind speed in m/s
+    pub wind: Option<Input>,
+    /// Station pressure in hPa
+    pub pressure: Option<Input>,
+}
+
+impl Inputs {
+    pub fn lookup(sensors: &Sensors, conf: &SensorsConf, quality: &QualityConf) -> Self {
+        let input = |key, base| Input::lookup(sensors, conf, quality, key, base);
+        Self {
+            temp: input("temp", Some(Unit::Celsius)),
+            humidity: input("humidity", None),
+            wind: input("windspd", Some(Unit::MetersPerSecond)),
+            pressure: input("barom", Some(Unit::Hectopascal)),
+        }
+    }
+}
+
+/// Partial pressure of water vapor in hPa
+pub fn vapor_pressure(temp: f32, humidity: f32) -> f32 {
+    humidity / 100.0 * 6.1094 * (MAGNUS_A * temp / (MAGNUS_B + temp)).exp()
+}
+
+/// https://www.omnicalculator.com/physics/dew-point#how-to-calculate-dew-point-how-to-calculate-relative-humidity
+pub fn dewpoint(temp: f32, humidity: f32) -> f32 {
+    let a = (humidity / 100.0).ln() + (MAGNUS_A * temp / (MAGNUS_B + temp));
+    (MAGNUS_B * a) / (MAGNUS_A - a)
+}
+
+/// Grams of water per cubic meter of air
+pub fn absolute_humidity(temp: f32, humidity: f32) -> f32 {
+    216.7 * vapor_pressure(temp, humidity) / (temp + 273.15)
+}
+
+/// NWS heat index, using the Rothfusz regression when it's hot enough
+///
+/// https://www.wpc.ncep.noaa.gov/html/heatindex_equation.shtml
+pub fn heat_index(temp: f32, humidity: f32) -> f32 {
+    let t = Unit::Celsius.convert(temp, Unit::Fahrenheit).unwrap();
+    let rh = humidity;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+    Unit::Fahrenheit.convert(hi, Unit::Celsius).unwrap()
+}
+
+/// Environment Canada wind chill, or the temperature when it doesn't apply
+pub fn wind_chill(temp: f32, wind: f32) -> f32 {
+    let v = Unit::MetersPerSecond
+        .convert(wind, Unit::KilometersPerHour)
+        .unwrap();
+    if temp > 10.0 || v <= 4.8 {
+        return temp;
+    }
+    let v = v.powf(0.16);
+    13.12 + 0.6215 * temp - 11.37 * v + 0.3965 * temp * v
+}
+
+/// Wind chill when it's cold, heat index when it's hot
+pub fn feels_like(temp: f32, humidity: Option<f32>, wind: Option<f32>) -> f32 {
+    match (humidity, wind) {
+        (_, Some(wind)) if temp <= 10.0 => wind_chill(temp, wind),
+        (Some(humidity), _) if temp >= 26.7 => heat_index(temp, humidity),
+        _ => temp,
+    }
+}
+
+pub fn humidex(temp: f32, humidity: f32) -> f32 {
+    let dewpoint = dewpoint(temp, humidity) + 273.15;
+    let e = 6.11 * (5417.753 * (1.0 / 273.16 - 1.0 / dewpoint)).exp();
+    temp + 0.5555 * (e - 10.0)
+}
+
+/// Stull's wet-bulb temperature, good between 5% and 99% humidity
+///
+/// https://doi.org/10.1175/JAMC-D-11-0143.1
+pub fn wet_bulb(temp: f32, humidity: f32) -> f32 {
+    let rh = humidity;
+    temp * (0.151977 * (rh + 8.313659).sqrt()).atan() + (temp + rh).atan() - (rh - 1.676331).atan()
+        + 0.00391838 * rh.powf(1.5) * (0.023101 * rh).atan()
+        - 4.686035
+}
+
+/// Height of cumulus cloud base above the station in meters
+pub fn cloud_base(temp: f32, humidity: f32) -> f32 {
+    125.0 * (temp - dewpoint(temp, humidity))
+}
+
+/// Gas constant of dry air in J/(kg K)
+const R_DRY: f32 = 287.05;
+/// ISA temperature lapse rate in K/m
+const LAPSE_RATE: f32 = 0.0065;
+/// ISA sea-level temperature in K
+const ISA_TEMP: f32 = 288.15;
+
+/// Gravity at sea level for a latitude in degrees
+fn gravity(latitude: f32) -> f32 {
+    let cos = (2.0 * latitude.to_radians()).cos();
+    9.80616 * (1.0 - 0.0026373 * cos + 0.0000059 * cos * cos)
+}
+
+/// Station pressure reduced to sea level through the current temperature
+///
+/// Assumes a standard lapse rate below the station, so the air column is
+/// as warm as the station plus half of the lapse over its height.
+pub fn qff(pressure: f32, temp: f32, elevation: f32, latitude: f32) -> f32 {
+    let column = temp + 273.15 + LAPSE_RATE * elevation / 2.0;
+    pressure * (gravity(latitude) * elevation / (R_DRY * column)).exp()
+}
+
+/// Station pressure reduced to sea level through the standard atmosphere
+pub fn qnh(pressure: f32, elevation: f32) -> f32 {
+    let exponent = 9.80665 / (R_DRY * LAPSE_RATE);
+    pressure * (ISA_TEMP / (ISA_TEMP - LAPSE_RATE * elevation)).powf(exponent)
+}
+
+/// The NWS altimeter setting
+///
+/// https://www.weather.gov/media/epz/wxcalc/altimeterSetting.pdf
+pub fn altimeter(pressure: f32, elevation: f32) -> f32 {
+    const N: f32 = 0.190284;
+    let p = pressure - 0.3;
+    let k = 1013.25f32.powf(N) * LAPSE_RATE / ISA_TEMP;
+    p * (1.0 + k * elevation / p.powf(N)).powf(1.0 / N)
+}
+
+/// Add every enabled derived quantity to an update
+///
+/// Temperatures are published in the unit the station reports temperature in.
+/// `elevation` is in meters and `latitude` in degrees.
+pub fn publish(
+    builder: &mut ReportBuilder,
+    conf: &DerivedConf,
+    inputs: &Inputs,
+    elevation: f32,
+    latitude: f32,
+) {
+    let temp_unit = inputs.temp.and_then(|t| t.unit).unwrap_or(Unit::Celsius);
+    let as_temp = |c: f32| Unit::Celsius.convert(c, temp_unit).unwrap();
+    let th = inputs.temp.zip(inputs.humidity);
+
+    let mut add = |enabled: bool, key: &str, unit: &str, value: Option<(f32, Input)>| {
+        if !enabled {
+            return;
+        }
+        match value {
+            Some((value, input)) => {
+                builder.value(key, unit, value, input.measured, input.quality);
+            }
+            None => {
+                builder.missing(key);
+            }
+        }
+    };
+    // Anything computed from temperature and humidity
+    let with_th = |f: fn(f32, f32) -> f32| th.map(|(t, h)| (f(t.value, h.value), t.and(h)));
+    let temp_symbol = temp_unit.symbol();
+
+    add(
+        conf.dewpoint,
+        "dewpoint",
+        temp_symbol,
+        with_th(dewpoint).map(|(v, i)| (as_temp(v), i)),
+    );
+    add(
+        conf.heat_index,
+        "heatindex",
+        temp_symbol,
+        with_th(heat_index).map(|(v, i)| (as_temp(v), i)),
+    );
+    add(
+        conf.wind_chill,
+        "windchill",
+        temp_symbol,
+        inputs
+            .temp
+            .zip(inputs.wind)
+            .map(|(t, w)| (as_temp(wind_chill(t.value, w.value)), t.and(w))),
+    );
+    add(
+        conf.feels_like,
+        "feelslike",
+        temp_symbol,
+        inputs.temp.map(|t| {
+            let mut input = t;
+            for other in [inputs.humidity, inputs.wind].into_iter().flatten() {
+                input = input.and(other);
+            }
+            let v = feels_like(
+                t.value,
+                inputs.humidity.map(|h| h.value),
+                inputs.wind.map(|w| w.value),
+            );
+            (as_temp(v), input)
+        }),
+    );
+    add(conf.humidex, "humidex", "index", with_th(humidex));
+    add(
+        conf.absolute_humidity,
+        "abshumidity",
+        "g/m3",
+        with_th(absolute_humidity),
+    );
+    add(
+        conf.vapor_pressure,
+        "vaporpressure",
+        "hPa",
+        with_th(vapor_pressure),
+    );
+    add(
+        conf.wet_bulb,
+        "wetbulb",
+        temp_symbol,
+        with_th(wet_bulb).map(|(v, i)| (as_temp(v), i)),
+    );
+    add(conf.cloud_base, "cloudbase", "m", with_th(cloud_base));
+
+    add(
+        conf.qff,
+        "barom-qff",
+        "hPa",
+        inputs
+            .pressure
+            .zip(inputs.temp)
+            .map(|(p, t)| (qff(p.value, t.value, elevation, latitude), p.and(t))),
+    );
+    add(
+        conf.qnh,
+        "barom-qnh",
+        "hPa",
+        inputs.pressure.map(|p| (qnh(p.value, elevation), p)),
+    );
+    add(
+        conf.altimeter,
+        "barom-altimeter",
+        "hPa",
+        inputs.pressure.map(|p| (altimeter(p.value, elevation), p)),
+    );
+}
@@ -0,0 +1,391 @@
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    conf::{DayConf, QualityConf, SensorsConf},
+    mqtt, report,
+    sensor::Sensors,
+    state,
+    units::UnitSystem,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Extreme {
+    Highest,
+    Lowest,
+}
+
+impl Extreme {
+    fn beats(self, value: f32, record: f32) -> bool {
+        match self {
+            Self::Highest => value > record,
+            Self::Lowest => value < record,
+        }
+    }
+}
+
+/// The extremes kept for each day
+///
+/// Each is the published key, the key of the sensor it comes from and the key
+/// of the wind heading to record along with it.
+const TRACKED: [(&str, &str, Extreme, Option<&str>); 7] = [
+    ("temp-high", "temp", Extreme::Highest, None),
+    ("temp-low", "temp", Extreme::Lowest, None),
+    ("windgust-max", "windspd", Extreme::Highest, Some("winddir")),
+    ("barom-high", "barom", Extreme::Highest, None),
+    ("barom-low", "barom", Extreme::Lowest, None),
+    ("humidity-high", "humidity", Extreme::Highest, None),
+    ("humidity-low", "humidity", Extreme::Lowest, None),
+];
+
+/// Year, month and day of a date
+fn date_key(date: NaiveDate) -> (i32, u32, u32) {
+    (date.year(), date.month(), date.day())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    value: f32,
+    unit: String,
+    /// Unix time of the record
+    time: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Day {
+    /// Year, month and day of the weather day
+    date: (i32, u32, u32),
+    records: HashMap<String, Record>,
+}
+
+impl Day {
+    fn report(&self, units: UnitSystem, conf: &DayConf) -> mqtt::DayExtremes {
+        let (year, month, day) = self.date;
+        let extremes = self
+            .records
+            .iter()
+            .map(|(key, record)| {
+                let (value, unit) = units.convert(record.value, &record.unit);
+                let time = Utc
+                    .timestamp_opt(record.time, 0)
+                    .single()
+                    .map(|t| conf.rfc3339(t))
+                    .unwrap_or_default();
+                let extreme = mqtt::Extreme {
+                    value,
+                    unit: unit.to_owned(),
+                    time,
+                    dir: record.dir,
+                };
+                (key.clone(), extreme)
+            })
+            .collect();
+        mqtt::DayExtremes {
+            date: format!("{year:04}-{month:02}-{day:02}"),
+            extremes,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Days {
+    today: Option<Day>,
+    yesterday: Option<Day>,
+}
+
+impl Days {
+    /// The day that `date` is part of, if it is `today` or the day before
+    fn get_mut(&mut self, today: NaiveDate, date: NaiveDate) -> Option<&mut Day> {
+        if date == today {
+            return self.today.as_mut();
+        }
+        if Some(date) != today.pred_opt() {
+            return None;
+        }
+        Some(self.yesterday.get_or_insert_with(|| Day {
+            date: date_key(date),
+            records: HashMap::new(),
+        }))
+    }
+}
+
+/// The highs and lows of today and yesterday
+pub struct Extremes {
+    path: PathBuf,
+    days: Days,
+    /// When the histories were last looked through
+    checked: Option<Instant>,
+}
+
+impl Extremes {
+    /// Pick up the extremes from before the last restart
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join("extremes.json");
+        Self {
+            days: state::load(&path),
+            path,
+            checked: None,
+        }
+    }
+
+    /// Move today to yesterday once `date` has started
+    ///
+    /// Returns whether the day changed.
+    fn roll(&mut self, date: NaiveDate) -> bool {
+        let today = date_key(date);
+        if self.days.today.as_ref().map(|d| d.date) == Some(today) {
+            return false;
+        }
+        let yesterday = date.pred_opt().map(date_key);
+        // Nothing is left of yesterday if the daemon was down through it
+        self.days.yesterday = self.days.today.take().filter(|d| Some(d.date) == yesterday);
+        self.days.today = Some(Day {
+            date: today,
+            records: HashMap::new(),
+        });
+        true
+    }
+
+    /// Look for new extremes in every reading since the last update
+    ///
+    /// Readings are credited to the day they were taken on, so the ones from
+    /// just before the day changed still count towards yesterday. `rain_rate`
+    /// is in mm/h.
+    pub fn update(
+        &mut self,
+        day: &DayConf,
+        sensors: &Sensors,
+        sensors_conf: &SensorsConf,
+        quality: &QualityConf,
+        rain_rate: Option<f32>,
+    ) {
+        let now = Instant::now();
+        let wall = Utc::now();
+        let today = day.date(wall);
+        let mut changed = self.roll(today);
+        let window = self
+            .checked
+            .replace(now)
+            .map_or(Duration::MAX, |checked| now - checked);
+
+        for (key, source, extreme, heading) in TRACKED {
+            let sensor = match report::lookup(sensors, sensors_conf, source).first() {
+                Some(sensor) => *sensor,
+                None => continue,
+            };
+            let history = match sensors.history(&sensor.name) {
+                Some(history) => history,
+                None => continue,
+            };
+            let heading = heading
+                .and_then(|h| report::lookup(sensors, sensors_conf, h).first().copied())
+                .and_then(|h| sensors.history(&h.name));
+            for sample in history.since(window) {
+                if !quality.in_range(&sensor.name, sample.value) {
+                    continue;
+                }
+                let age = chrono::Duration::from_std(now.saturating_duration_since(sample.time))
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+                let time = wall - age;
+                let records = match self.days.get_mut(today, day.date(time)) {
+                    Some(d) => &mut d.records,
+                    None => continue,
+                };
+                if let Some(record) = records.get(key) {
+                    if !extreme.beats(sample.value, record.value) {
+                        continue;
+                    }
+                }
+                let record = Record {
+                    value: sample.value,
+                    unit: sensor.unit.to_string(),
+                    time: time.timestamp(),
+                    dir: heading
+                        .and_then(|h| h.nearest(sample.time))
+                        .map(|h| h.value),
+                };
+                records.insert(key.into(), record);
+                changed = true;
+            }
+        }
+
+        if let Some(rate) = rain_rate {
+            let records = &mut self.days.today.as_mut().unwrap().records;
+            let record = records.get("rainrate-max").map_or(0.0, |r| r.value);
+            if rate > record {
+                let record = Record {
+                    value: rate,
+                    unit: "mm/h".into(),
+                    time: wall.timestamp(),
+                    dir: None,
+                };
+                records.insert("rainrate-max".into(), record);
+                changed = true;
+            }
+        }
+
+        if changed {
+            if let Err(err) = state::save(&self.path, &self.days) {
+                eprintln!("could not save the extremes: {err}");
+            }
+        }
+    }
+
+    /// The extremes to publish, in `units`
+    ///
+    /// Their times are in the timezone of the weather day.
+    pub fn report(&self, units: UnitSystem, day: &DayConf) -> Option<mqtt::Extremes> {
+        Some(mqtt::Extremes {
+            time: day.rfc3339(Utc::now()),
+            today: self.days.today.as_ref()?.report(units, day),
+            yesterday: self.days.yesterday.as_ref().map(|d| d.report(units, day)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::TimeZone;
+    use chrono::FixedOffset;
+    use std::fs;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn record(value: f32, time: i64) -> Record {
+        Record {
+            value,
+            unit: "C".into(),
+            time,
+            dir: None,
+        }
+    }
+
+    fn extremes(path: &Path) -> Extremes {
+        Extremes {
+            path: path.into(),
+            days: Days::default(),
+            checked: None,
+        }
+    }
+
+    #[test]
+    fn beats() {
+        assert!(Extreme::Highest.beats(2.0, 1.0));
+        assert!(!Extreme::Highest.beats(1.0, 1.0));
+        assert!(!Extreme::Highest.beats(0.5, 1.0));
+        assert!(Extreme::Lowest.beats(0.5, 1.0));
+        assert!(!Extreme::Lowest.beats(1.0, 1.0));
+        assert!(!Extreme::Lowest.beats(2.0, 1.0));
+    }
+
+    #[test]
+    fn roll() {
+        let mut e = extremes(Path::new("extremes.json"));
+        assert!(e.roll(date(2024, 3, 1)));
+        assert!(!e.roll(date(2024, 3, 1)));
+        let today = e.days.today.as_mut().unwrap();
+        today.records.insert("temp-high".into(), record(20.0, 0));
+
+        // Across a month boundary
+        assert!(e.roll(date(2024, 3, 2)));
+        let yesterday = e.days.yesterday.as_ref().unwrap();
+        assert_eq!(yesterday.date, (2024, 3, 1));
+        assert!(yesterday.records.contains_key("temp-high"));
+        let today = e.days.today.as_ref().unwrap();
+        assert_eq!(today.date, (2024, 3, 2));
+        assert!(today.records.is_empty());
+
+        // A day was skipped, so yesterday is gone
+        assert!(e.roll(date(2024, 3, 4)));
+        assert!(e.days.yesterday.is_none());
+        assert_eq!(e.days.today.as_ref().unwrap().date, (2024, 3, 4));
+    }
+
+    #[test]
+    fn readings_go_to_their_own_day() {
+        let mut days = Days::default();
+        let today = date(2024, 1, 1);
+        days.today = Some(Day {
+            date: date_key(today),
+            records: HashMap::new(),
+        });
+
+        assert_eq!(days.get_mut(today, today).unwrap().date, (2024, 1, 1));
+        assert!(days.yesterday.is_none());
+        assert_eq!(
+            days.get_mut(today, date(2023, 12, 31)).unwrap().date,
+            (2023, 12, 31)
+        );
+        assert!(days.yesterday.is_some());
+        assert!(days.get_mut(today, date(2023, 12, 30)).is_none());
+        assert!(days.get_mut(today, date(2024, 1, 2)).is_none());
+    }
+
+    #[test]
+    fn report_in_day_timezone() {
+        let day = Day {
+            date: (2024, 6, 1),
+            records: [("temp-high".to_owned(), record(25.0, 1717243200))].into(),
+        };
+        let mut conf = DayConf {
+            reset_hour: 0,
+            timezone: TimeZone::Fixed(FixedOffset::west_opt(7 * 3600).unwrap()),
+        };
+        let report = day.report(UnitSystem::Station, &conf);
+        assert_eq!(report.date, "2024-06-01");
+        assert_eq!(
+            report.extremes["temp-high"].time,
+            "2024-06-01T05:00:00-07:00"
+        );
+
+        conf.timezone = TimeZone::Utc;
+        let report = day.report(UnitSystem::Station, &conf);
+        assert_eq!(
+            report.extremes["temp-high"].time,
+            "2024-06-01T12:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn persistence_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("station-comms-extremes-{}", std::process::id()));
+        let mut e = Extremes::load(&dir);
+        assert!(e.days.today.is_none());
+        e.roll(date(2024, 3, 1));
+        let today = e.days.today.as_mut().unwrap();
+        today
+            .records
+            .insert("temp-low".into(), record(-3.5, 1709251200));
+        e.roll(date(2024, 3, 2));
+        let mut gust = record(12.0, 1709337600);
+        gust.dir = Some(270.0);
+        let today = e.days.today.as_mut().unwrap();
+        today.records.insert("windgust-max".into(), gust);
+        state::save(&e.path, &e.days).unwrap();
+
+        let loaded = Extremes::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let yesterday = loaded.days.yesterday.unwrap();
+        assert_eq!(yesterday.date, (2024, 3, 1));
+        let low = &yesterday.records["temp-low"];
+        assert_eq!((low.value, low.time, low.dir), (-3.5, 1709251200, None));
+        let today = loaded.days.today.unwrap();
+        assert_eq!(today.date, (2024, 3, 2));
+        let gust = &today.records["windgust-max"];
+        assert_eq!(
+            (gust.value, &*gust.unit, gust.dir),
+            (12.0, "C", Some(270.0))
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use chrono::Datelike;
+use std::time::{Duration, Instant};
+
+use crate::{
+    conf::{DerivedConf, QualityConf, SensorsConf},
+    derived,
+    history::History,
+    mqtt::InfoForecast,
+    report::{self, ReportBuilder},
+    sensor::{Quality, Sensors},
+    units::{Unit, UnitSystem},
+};
+
+const TENDENCY_WINDOW: Duration = Duration::from_secs(3 * 60 * 60);
+/// Least history needed for a tendency, scaled up to the full window
+const TENDENCY_MIN_WINDOW: Duration = Duration::from_secs(150 * 60);
+/// Changes in hPa smaller than this are steady
+const STEADY: f32 = 0.1;
+
+/// How the pressure changed over the last 3 hours
+#[derive(Debug, Clone, Copy)]
+pub struct Tendency {
+    /// Change in hPa
+    pub change: f32,
+    /// WMO code table 0200
+    pub code: u8,
+}
+
+impl Tendency {
+    /// Work out the tendency from a pressure history in `unit`
+    pub fn from_history(history: &History, unit: Unit) -> Option<Self> {
+        let oldest = history.at(TENDENCY_WINDOW)?;
+        let age = oldest.time.elapsed();
+        if age < TENDENCY_MIN_WINDOW {
+            return None;
+        }
+        let middle = history.at(age / 2)?;
+        let latest = history.at(Duration::ZERO)?;
+        let hpa = |v: f32| unit.convert(v, Unit::Hectopascal);
+        let (p0, p1, p2) = (hpa(oldest.value)?, hpa(middle.value)?, hpa(latest.value)?);
+
+        let scale = TENDENCY_WINDOW.as_secs_f32() / age.as_secs_f32();
+        Some(Self {
+            change: (p2 - p0) * scale,
+            code: wmo_code(p1 - p0, p2 - p1, p2 - p0),
+        })
+    }
+
+    /// The Met Office description of the tendency
+    pub fn describe(&self) -> &'static str {
+        let change = self.change.abs();
+        let rising = self.change > 0.0;
+        match change {
+            c if c < STEADY => "steady",
+            c if c < 1.6 && rising => "rising slowly",
+            c if c < 1.6 => "falling slowly",
+            c if c < 3.6 && rising => "rising",
+            c if c < 3.6 => "falling",
+            c if c < 6.0 && rising => "rising quickly",
+            c if c < 6.0 => "falling quickly",
+            _ if rising => "rising very rapidly",
+            _ => "falling very rapidly",
+        }
+    }
+}
+
+/// Characteristic of the pressure tendency from the changes over each half
+/// of the window
+fn wmo_code(first: f32, second: f32, total: f32) -> u8 {
+    let up = |d: f32| d > STEADY;
+    let down = |d: f32| d < -STEADY;
+    if total > STEADY {
+        if up(first) && down(second) {
+            0
+        } else if up(first) && (!up(second) || second < first) {
+            1
+        } else if !up(first) || second > first {
+            3
+        } else {
+            2
+        }
+    } else if total < -STEADY {
+        if down(first) && up(second) {
+            5
+        } else if down(first) && (!down(second) || second > first) {
+            6
+        } else if !down(first) || second < first {
+            8
+        } else {
+            7
+        }
+    } else if up(first) && down(second) {
+        0
+    } else if down(first) && up(second) {
+        5
+    } else {
+        4
+    }
+}
+
+const ZAMBRETTI: [&str; 26] = [
+    "Settled fine",
+    "Fine weather",
+    "Becoming fine",
+    "Fine, becoming less settled",
+    "Fine, possible showers",
+    "Fairly fine, improving",
+    "Fairly fine, possible showers early",
+    "Fairly fine, showery later",
+    "Showery early, improving",
+    "Changeable, mending",
+    "Fairly fine, showers likely",
+    "Rather unsettled clearing later",
+    "Unsettled, probably improving",
+    "Showery, bright intervals",
+    "Showery, becoming less settled",
+    "Changeable, some rain",
+    "Unsettled, short fine intervals",
+    "Unsettled, rain later",
+    "Unsettled, some rain",
+    "Mostly very unsettled",
+    "Occasional rain, worsening",
+    "Rain at times, very unsettled",
+    "Rain at frequent intervals",
+    "Rain, very unsettled",
+    "Stormy, may improve",
+    "Stormy, much rain",
+];
+
+/// Forecasts by pressure, from 950 to 1050 hPa
+const RISING_OPTIONS: [u8; 22] = [
+    25, 25, 25, 24, 24, 19, 16, 12, 11, 9, 8, 6, 5, 2, 1, 1, 0, 0, 0, 0, 0, 0,
+];
+const STEADY_OPTIONS: [u8; 22] = [
+    25, 25, 25, 25, 25, 25, 23, 23, 22, 18, 15, 13, 10, 4, 1, 1, 0, 0, 0, 0, 0, 0,
+];
+const FALLING_OPTIONS: [u8; 22] = [
+    25, 25, 25, 25, 25, 25, 25, 25, 23, 23, 21, 20, 17, 14, 7, 3, 1, 1, 1, 0, 0, 0,
+];
+
+/// hPa added to the pressure for wind from each of the 16 compass points,
+/// starting at north
+const WIND_ADJUSTMENT: [f32; 16] = [
+    6.0, 5.0, 5.0, 2.0, -0.5, -2.0, -5.0, -8.5, -12.0, -10.0, -6.0, -4.5, -3.0, -0.5, 1.5, 3.0,
+];
+
+/// A Zambretti forecast
+#[derive(Debug, Clone, Copy)]
+pub struct Zambretti {
+    pub letter: char,
+    pub text: &'static str,
+}
+
+impl Zambretti {
+    /// Forecast from sea-level pressure and its 3 hour change, both in hPa
+    ///
+    /// The wind direction is in degrees. The southern hemisphere has its
+    /// winds and seasons flipped.
+    pub fn new(sea_level: f32, change: f32, wind: Option<f32>, latitude: f32, month: u32) -> Self {
+        let south = latitude < 0.0;
+        let mut pressure = sea_level;
+        if let Some(wind) = wind {
+            let wind = if south { wind + 180.0 } else { wind };
+            let point = (wind.rem_euclid(360.0) / 22.5).round() as usize % 16;
+            pressure += WIND_ADJUSTMENT[point];
+        }
+        let summer = (4..=9).contains(&month) != south;
+        let options = if change >= 1.6 {
+            if summer {
+                pressure += 7.0;
+            }
+            &RISING_OPTIONS
+        } else if change <= -1.6 {
+            if !summer {
+                pressure -= 7.0;
+            }
+            &FALLING_OPTIONS
+        } else {
+            &STEADY_OPTIONS
+        };
+        let step = 100.0 / options.len() as f32;
+        let i = ((pressure - 950.0) / step).floor().clamp(0.0, 21.0) as usize;
+        let forecast = options[i];
+        Self {
+            letter: (b'A' + forecast) as char,
+            text: ZAMBRETTI[forecast as usize],
+        }
+    }
+}
+
+/// The pressure tendency and the forecast from it
+#[derive(Debug, Clone, Copy)]
+pub struct Forecast {
+    pub tendency: Tendency,
+    pub zambretti: Zambretti,
+    pub measured: Instant,
+    pub quality: Quality,
+}
+
+impl Forecast {
+    /// Forecast from the pressure and wind direction sensors
+    ///
+    /// Needs about 3 hours of pressure history.
+    pub fn new(
+        sensors: &Sensors,
+        conf: &SensorsConf,
+        quality: &QualityConf,
+        elevation: f32,
+        latitude: f32,
+    ) -> Option<Self> {
+        let pressure = report::lookup(sensors, conf, "barom").into_iter().next()?;
+        let unit = Unit::parse(&pressure.unit)?;
+        let tendency = Tendency::from_history(sensors.history(&pressure.name)?, unit)?;
+        let sea_level = derived::qnh(unit.convert(pressure.value, Unit::Hectopascal)?, elevation);
+        let wind = report::lookup(sensors, conf, "winddir")
+            .into_iter()
+            .next()
+            .map(|w| w.value);
+        let month = chrono::Local::now().month();
+        Some(Self {
+            tendency,
+            zambretti: Zambretti::new(sea_level, tendency.change, wind, latitude, month),
+            measured: pressure.last_update,
+            quality: pressure.quality(quality),
+        })
+    }
+
+    /// The forecast for the info topic, with the tendency in `units`
+    pub fn info(&self, units: UnitSystem) -> InfoForecast {
+        let (tendency, unit) = units.convert(self.tendency.change, "hPa");
+        InfoForecast {
+            tendency,
+            tendency_unit: unit.into(),
+            tendency_code: self.tendency.code,
+            trend: self.tendency.describe().into(),
+            code: self.zambretti.letter,
+            forecast: self.zambretti.text.into(),
+        }
+    }
+}
+
+/// Add the tendency and forecast to an update, if they are enabled
+pub fn publish(builder: &mut ReportBuilder, conf: &DerivedConf, forecast: Option<&Forecast>) {
+    if conf.tendency {
+        match forecast {
+            Some(f) => {
+                let t = f.tendency;
+                builder.value("barom-tendency", "hPa", t.change, f.measured, f.quality);
+                builder.value(
+                    "barom-tendency-code",
+                    "wmo",
+                    t.code as f32,
+                    f.measured,
+                    f.quality,
+                );
+                builder.text("barom-trend", t.describe());
+            }
+            None => {
+                builder.missing("barom-tendency");
+                builder.missing("barom-tendency-code");
+                builder.missing_text("barom-trend");
+            }
+        }
+    }
+    if conf.forecast {
+        match forecast {
+            Some(f) => {
+                builder.text("forecast", f.zambretti.text);
+                builder.text("forecast-code", f.zambretti.letter.to_string());
+            }
+            None => {
+                builder.missing_text("forecast");
+                builder.missing_text("forecast-code");
+            }
+        }
+    }
+}
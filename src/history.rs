@@ -0,0 +1,151 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub time: Instant,
+    pub value: f32,
+}
+
+/// Statistics over the samples of a window
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Population standard deviation
+    pub stddev: f32,
+}
+
+/// The recent values of a sensor
+///
+/// Samples older than `window` are dropped, and so are the oldest samples
+/// once there are more than `capacity` of them.
+pub struct History {
+    samples: VecDeque<Sample>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl std::fmt::Debug for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The samples would drown out everything else
+        f.debug_struct("History")
+            .field("samples", &self.samples.len())
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl History {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window,
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, time: Instant, value: f32) {
+        self.samples.push_back(Sample { time, value });
+        while self.samples.len() > self.capacity {
+            self.samples.pop_front();
+        }
+        while let Some(sample) = self.samples.front() {
+            if time.saturating_duration_since(sample.time) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Every sample from the last `window`, oldest first
+    pub fn since(&self, window: Duration) -> impl Iterator<Item = &Sample> {
+        let now = Instant::now();
+        // Samples are in order, so skip straight to the start of the window
+        let start = self
+            .samples
+            .partition_point(|s| now.saturating_duration_since(s.time) > window);
+        self.samples.range(start..)
+    }
+
+    /// The sample closest to `ago` in the past
+    pub fn at(&self, ago: Duration) -> Option<Sample> {
+        self.nearest(Instant::now().checked_sub(ago)?)
+    }
+
+    /// The sample closest to `then`
+    pub fn nearest(&self, then: Instant) -> Option<Sample> {
+        let i = self.samples.partition_point(|s| s.time < then);
+        let after = self.samples.get(i);
+        let before = i.checked_sub(1).and_then(|i| self.samples.get(i));
+        match (before, after) {
+            (Some(b), Some(a)) if then - b.time < a.time - then => Some(*b),
+            (_, Some(a)) => Some(*a),
+            (b, None) => b.copied(),
+        }
+    }
+
+    pub fn stats(&self, window: Duration) -> Option<Stats> {
+        let mut samples = self.since(window);
+        let first = samples.next()?.value;
+        let mut stats = Stats {
+            count: 1,
+            min: first,
+            max: first,
+            mean: first,
+            stddev: 0.0,
+        };
+        // Welford's algorithm, with the running sum of squares kept in stddev
+        for sample in samples {
+            stats.count += 1;
+            stats.min = stats.min.min(sample.value);
+            stats.max = stats.max.max(sample.value);
+            let delta = sample.value - stats.mean;
+            stats.mean += delta / stats.count as f32;
+            stats.stddev += delta * (sample.value - stats.mean);
+        }
+        stats.stddev = (stats.stddev / stats.count as f32).sqrt();
+        Some(stats)
+    }
+}
+
+/// The histories of every sensor, by name
+///
+/// Keeping them by name lets a history survive the station renumbering its
+/// sensors.
+#[derive(Debug)]
+pub struct Histories {
+    histories: HashMap<Arc<str>, History>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl Histories {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            histories: HashMap::new(),
+            window,
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, name: &str, time: Instant, value: f32) {
+        match self.histories.get_mut(name) {
+            Some(history) => history.push(time, value),
+            None => {
+                let mut history = History::new(self.window, self.capacity);
+                history.push(time, value);
+                self.histories.insert(name.into(), history);
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&History> {
+        self.histories.get(name)
+    }
+}
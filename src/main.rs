@@ -7,42 +7,289 @@ use std::{
     time::{Duration, Instant},
 };
 
-use color_eyre::{eyre::Context, install, Result};
+use color_eyre::{
+    eyre::{eyre, Context},
+    install, Result,
+};
 use mqtt::{Mqtt, Request};
 use ordoo::or_do;
 use paho_mqtt::{Client, ConnectOptions};
-use rppal::uart::Uart;
+use protocol::{AutosOp, SensorMask, StationCommand, StationMessage};
 use scode_rs::{error::ScodeError, CodeSend};
-use sensor::Sensors;
-use station::{
-    request_all_sensors_code, request_autos_code, request_sensor_code, set_clock_code, CodeHandler,
-    CommandManager,
-};
+use sensor::{SensorEvent, Sensors};
+use station::{CodeHandler, CommandManager, LinkState, PendingResponse, Response, RetryPolicy};
 
 use crate::{
+    capture::{CaptureTransport, ReplayTransport},
     conf::Conf,
-    mqtt::{SensorValue, Update},
-    sensor::Sensor,
+    extremes::Extremes,
+    forecast::Forecast,
+    history::Histories,
+    rain::RainGauge,
+    report::ReportBuilder,
     station::StationReader,
+    transport::Transport,
+    units::UnitSystem,
 };
 
+mod capture;
 mod conf;
+mod derived;
+mod extremes;
+mod forecast;
+mod history;
 mod mqtt;
+mod protocol;
+mod rain;
+mod report;
 mod sensor;
+mod state;
 mod station;
+mod transport;
+mod units;
+mod wind;
+
+/// Poll every sensor that the station doesn't push on its own
+///
+/// Auto reported sensors are only polled once their last push is older than
+/// `auto_timeout`.
+fn get_updates(
+    sensors: Arc<Mutex<Sensors>>,
+    commands: Arc<CommandManager>,
+    auto_timeout: Duration,
+) -> Result<()> {
+    let policy = RetryPolicy::new(Duration::from_secs(1))
+        .max_attempts(3)
+        .deadline(Duration::from_secs(5));
+    let now = Instant::now();
+    let pending: Vec<_> = sensors
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|sensor| !sensor.auto || now - sensor.last_update > auto_timeout)
+        .map(|sensor| {
+            let code = StationCommand::RequestSensor {
+                id: sensor.id,
+                only_value: true,
+            };
+            commands.request_async(code.into(), policy)
+        })
+        .collect();
+
+    // Publish whatever came back rather than holding up the whole update
+    for p in pending {
+        if let Err(err) = p.wait() {
+            eprintln!("{err}");
+        }
+    }
+    Ok(())
+}
 
-fn get_updates(sensors: Arc<Mutex<Sensors>>, commands: Arc<CommandManager>) -> Result<()> {
-    let (tx, rx) = mpsc::channel();
-    let mut count = 0;
-    for sensor in sensors.lock().unwrap().iter() {
-        let code = request_sensor_code(sensor.id, true);
-        commands.command_guarentee(code, tx.clone(), Duration::from_secs(1));
-        count += 1;
+/// Work out the auto reporting changes asked for by an MQTT request
+fn autos_ops(sensors: &Sensors, request: &Request) -> Result<Vec<AutosOp>> {
+    let id = |name: &str| {
+        sensors
+            .get(name)
+            .map(|s| s.id)
+            .or_else(|| name.parse().ok())
+            .ok_or_else(|| eyre!("unknown sensor {name:?}"))
+    };
+    let sensor = || {
+        request
+            .sensor
+            .as_deref()
+            .ok_or_else(|| eyre!("{} requires a sensor", request.action))
+    };
+    Ok(match request.action.as_ref() {
+        "auto-enable" => vec![AutosOp::Enable(id(sensor()?)?)],
+        "auto-disable" => vec![AutosOp::Disable(id(sensor()?)?)],
+        _ => {
+            let names = or_do!(
+                request.sensors.as_ref(),
+                return Err(eyre!("{} requires a list of sensors", request.action))
+            );
+            let mut mask = SensorMask::default();
+            for name in names {
+                mask.insert(id(name.as_str())?);
+            }
+            vec![AutosOp::Set(mask)]
+        }
+    })
+}
+
+/// Change the auto reporting on the station, then publish the new state
+fn set_autos(
+    commands: &CommandManager,
+    sensors: &Mutex<Sensors>,
+    mqtt: &Mqtt,
+    ops: Vec<AutosOp>,
+) -> Result<()> {
+    commands.request(
+        StationCommand::SetAutos(ops).into(),
+        Duration::from_secs(10),
+    )?;
+    // Ask again rather than trusting the station to report the change
+    commands.request(StationCommand::RequestAutos.into(), Duration::from_secs(10))?;
+
+    let autos = sensors
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|s| (s.name.to_string(), s.auto))
+        .collect();
+    mqtt.publish_autos(mqtt::Autos {
+        time: chrono::Local::now().to_rfc3339(),
+        sensors: autos,
+    })
+}
+
+fn publish_sensor_event(mqtt: &Mqtt, event: SensorEvent) {
+    let time = chrono::Local::now().to_rfc3339();
+    let change = match event {
+        SensorEvent::Unknown(_) => return,
+        SensorEvent::Added { id, name, unit } => mqtt::SensorChange {
+            time,
+            event: mqtt::SensorChangeKind::Added,
+            sensor: id,
+            name: name.to_string(),
+            unit: unit.to_string(),
+            previous_name: None,
+            previous_unit: None,
+        },
+        SensorEvent::Changed {
+            id,
+            name,
+            unit,
+            old_name,
+            old_unit,
+        } => mqtt::SensorChange {
+            time,
+            event: mqtt::SensorChangeKind::Changed,
+            sensor: id,
+            name: name.to_string(),
+            unit: unit.to_string(),
+            previous_name: Some(old_name.to_string()),
+            previous_unit: Some(old_unit.to_string()),
+        },
+        SensorEvent::Removed { id, name, unit } => mqtt::SensorChange {
+            time,
+            event: mqtt::SensorChangeKind::Removed,
+            sensor: id,
+            name: name.to_string(),
+            unit: unit.to_string(),
+            previous_name: None,
+            previous_unit: None,
+        },
+    };
+    if let Err(err) = mqtt.publish_sensor_change(change) {
+        eprintln!("could not publish sensor change: {err}");
     }
+}
 
-    for _ in 0..count {
-        rx.recv()?;
+/// Statistics of the sensors asked for by an MQTT request, or of every sensor
+fn stats(sensors: &Sensors, request: &Request, max_window: f32, units: UnitSystem) -> mqtt::Stats {
+    let window = request.window.unwrap_or(max_window);
+    if !window.is_finite() || window < 0.0 {
+        return mqtt::Stats {
+            time: chrono::Local::now().to_rfc3339(),
+            window: None,
+            sensors: HashMap::new(),
+            error: Some(format!("{window} is not a valid window in seconds")),
+        };
+    }
+    let window = window.min(max_window);
+    // The longest window was checked when the configuration was loaded
+    let duration = Duration::from_secs_f32(window);
+    let names: Vec<String> = match (&request.sensor, &request.sensors) {
+        (Some(name), _) => vec![name.clone()],
+        (None, Some(names)) => names.clone(),
+        (None, None) => sensors.iter().map(|s| s.name.to_string()).collect(),
+    };
+    let sensors = names
+        .into_iter()
+        .filter_map(|name| {
+            let unit = &sensors.get(&name)?.unit;
+            let stats = sensors.stats(&name, duration)?;
+            let (mean, converted) = units.convert(stats.mean, unit);
+            // Units only differ in scale and offset, so the spread scales along
+            let stddev = units.convert(stats.mean + stats.stddev, unit).0 - mean;
+            Some((
+                name,
+                mqtt::SensorStats {
+                    unit: converted.into(),
+                    count: stats.count,
+                    min: units.convert(stats.min, unit).0,
+                    max: units.convert(stats.max, unit).0,
+                    mean,
+                    stddev,
+                },
+            ))
+        })
+        .collect();
+    mqtt::Stats {
+        time: chrono::Local::now().to_rfc3339(),
+        window: Some(window),
+        sensors,
+        error: None,
     }
+}
+
+/// Rebuild the sensors from the replies to an `M1` and publish what changed
+fn sync_sensors(sensors: &Mutex<Sensors>, mqtt: &Mqtt, response: Response) {
+    let reports = response
+        .replies
+        .iter()
+        .filter_map(|code| match StationMessage::parse(code) {
+            Ok(StationMessage::SensorReport(report)) => Some(report),
+            _ => None,
+        });
+    let report = sensors.lock().unwrap().resync(reports);
+    for conflict in &report.conflicts {
+        eprintln!("sensor conflict: {conflict}");
+    }
+    for event in report.events {
+        publish_sensor_event(mqtt, event);
+    }
+}
+
+/// Ask the station for all of its sensors and re-sync with them
+fn discover(sensors: &Mutex<Sensors>, commands: &CommandManager, mqtt: &Mqtt) -> Result<()> {
+    let response = commands.request(
+        StationCommand::RequestAllSensors.into(),
+        Duration::from_secs(30),
+    )?;
+    sync_sensors(sensors, mqtt, response);
+    Ok(())
+}
+
+/// Restart the station and start over with whatever sensors it comes back with
+fn reset(commands: &CommandManager, sensors: &Mutex<Sensors>, mqtt: &Mqtt) -> Result<()> {
+    commands.request(StationCommand::Reset.into(), Duration::from_secs(10))?;
+    let policy = RetryPolicy::new(Duration::from_secs(1))
+        .forever()
+        .deadline(Duration::from_secs(60));
+    finish_startup(startup(commands, policy), sensors, mqtt)
+}
+
+/// Bring the station up to date after it has connected
+///
+/// Returns the pending requests for the sensors and their auto reporting.
+fn startup(commands: &CommandManager, policy: RetryPolicy) -> [PendingResponse; 2] {
+    commands.command(StationCommand::set_clock_now().into());
+    [
+        commands.request_async(StationCommand::RequestAllSensors.into(), policy),
+        commands.request_async(StationCommand::RequestAutos.into(), policy),
+    ]
+}
+
+/// Wait for the station to answer `startup`
+fn finish_startup(
+    [all_sensors, autos]: [PendingResponse; 2],
+    sensors: &Mutex<Sensors>,
+    mqtt: &Mqtt,
+) -> Result<()> {
+    sync_sensors(sensors, mqtt, all_sensors.wait()?);
+    autos.wait()?;
     Ok(())
 }
 
@@ -50,6 +297,8 @@ enum ChannelType {
     Code(CodeSend),
     CodeErr(ScodeError),
     Request(Request),
+    Link(LinkState),
+    Sensor(SensorEvent),
 }
 
 impl From<CodeSend> for ChannelType {
@@ -70,17 +319,46 @@ impl From<Request> for ChannelType {
     }
 }
 
+impl From<SensorEvent> for ChannelType {
+    fn from(value: SensorEvent) -> Self {
+        Self::Sensor(value)
+    }
+}
+
+impl From<LinkState> for ChannelType {
+    fn from(value: LinkState) -> Self {
+        Self::Link(value)
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(version)]
 struct Args {
     #[arg(help = "Path to station.toml")]
     config: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Record all serial traffic to a capture in this directory"
+    )]
+    capture: Option<PathBuf>,
+    #[arg(long, help = "Replay a capture instead of talking to the station")]
+    replay: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Replay speed multiplier, 0 replays as fast as possible"
+    )]
+    speed: f32,
 }
 
 fn main() -> Result<()> {
     install()?;
 
     let args = Args::parse();
+    // The reader would otherwise retry a bad replay forever
+    if !args.speed.is_finite() || args.speed < 0.0 {
+        return Err(eyre!("--speed must be a finite, non-negative number"));
+    }
 
     let path = args.config.unwrap_or("station.toml".into());
     let conf = Conf::load(&path).with_context(|| format!("Could not open {path:?}"))?;
@@ -103,29 +381,50 @@ fn main() -> Result<()> {
 
     let (update, on_update) = mpsc::channel::<bool>();
 
-    let uart = Uart::with_path(
-        conf.serial.path,
-        conf.serial.baudrate,
-        conf.serial.parity.into(),
-        conf.serial.databits,
-        conf.serial.stopbits,
-    )?;
-    let sensors = Arc::new(Mutex::new(Sensors::new()));
+    let serial = conf.serial.clone();
+    let (replay, capture, speed) = (args.replay, args.capture, args.speed);
+    let connect = move || -> Result<Box<dyn Transport>> {
+        let transport: Box<dyn Transport> = match &replay {
+            Some(path) => Box::new(
+                ReplayTransport::open(path, speed)
+                    .with_context(|| format!("Could not open {path:?}"))?,
+            ),
+            None => transport::open(&serial)
+                .with_context(|| format!("Could not open the station on {:?}", serial.path))?,
+        };
+        Ok(match &capture {
+            Some(dir) => Box::new(CaptureTransport::create(dir, transport)?),
+            None => transport,
+        })
+    };
+    let history = Histories::new(
+        Duration::from_secs_f32(conf.history.window),
+        conf.history.max_samples,
+    );
+    let sensors = Arc::new(Mutex::new(Sensors::new(conf.calibration.clone(), history)));
 
-    let mut foo = StationReader::new(uart, tx, on_send);
-    thread::spawn(move || foo.main());
+    let events = tx.clone();
+    let mut reader = StationReader::new(connect, tx, on_send);
+    thread::spawn(move || reader.run());
 
     let mut code_handler = CodeHandler::new();
     code_handler.callback(commands.on_command());
-    code_handler.callback(Sensors::sensor_callback(&sensors));
+    code_handler.callback(commands.on_reply());
+    code_handler.callback(Sensors::sensor_callback(&sensors, events));
     code_handler.callback(Sensors::autos_callback(&sensors));
+    code_handler.callback(protocol::unhandled_callback());
 
     let cmd = commands.clone();
     let mut rapid = false;
     let mut rapid_due = Instant::now();
     let mut rapid_update_due = Instant::now();
     let mut update_due = Instant::now();
+    let mut link_lost = false;
     let mqt = mqtt.clone();
+    let snsrs = sensors.clone();
+    // The latest forecast, for the info
+    let latest_forecast = Arc::new(Mutex::new(None::<Forecast>));
+    let fcst = latest_forecast.clone();
     thread::spawn(move || loop {
         let cmd_due = cmd.earliest_due();
         let mut timeout = match cmd.earliest_due() {
@@ -162,7 +461,7 @@ fn main() -> Result<()> {
             }
             continue;
         }
-        let cmd = or_do!(rx.recv_timeout(timeout - now), _ => {
+        let msg = or_do!(rx.recv_timeout(timeout - now), _ => {
             let now = Instant::now();
             if let Some(due) = cmd_due {
                 if due <= now {
@@ -183,11 +482,65 @@ fn main() -> Result<()> {
             continue
         });
 
-        match cmd {
+        match msg {
             ChannelType::Code(code) => {
                 code_handler.code(code);
             }
             ChannelType::CodeErr(err) => eprintln!("{err}"),
+            ChannelType::Sensor(SensorEvent::Unknown(id)) => {
+                println!("discovered sensor {id}");
+                let code = StationCommand::RequestSensor {
+                    id,
+                    only_value: false,
+                };
+                let pending =
+                    cmd.request_async(code.into(), RetryPolicy::new(Duration::from_secs(1)));
+                let s = snsrs.clone();
+                thread::spawn(move || {
+                    let result = pending.wait();
+                    // Once answered the sensor is known, otherwise its next report asks again
+                    s.lock().unwrap().forget_unknown(id);
+                    if let Err(err) = result {
+                        eprintln!("could not discover sensor {id}: {err}");
+                    }
+                });
+            }
+            ChannelType::Sensor(event) => publish_sensor_event(&mqt, event),
+            ChannelType::Link(state) => {
+                let link = match &state {
+                    LinkState::Up => mqtt::Link {
+                        time: chrono::Local::now().to_rfc3339(),
+                        state: mqtt::LinkStatus::Up,
+                        error: None,
+                    },
+                    LinkState::Down(err) => mqtt::Link {
+                        time: chrono::Local::now().to_rfc3339(),
+                        state: mqtt::LinkStatus::Down,
+                        error: Some(err.clone()),
+                    },
+                };
+                if let Err(err) = mqt.publish_link(link) {
+                    eprintln!("could not publish link state: {err}");
+                }
+                match state {
+                    LinkState::Up => {
+                        if link_lost {
+                            // However slow the link is, the station has to be brought up to date
+                            let policy = RetryPolicy::new(Duration::from_secs(1)).forever();
+                            let pending = startup(&cmd, policy);
+                            let (s, m) = (snsrs.clone(), mqt.clone());
+                            // The sensors may have changed while the station was away
+                            thread::spawn(move || {
+                                if let Err(err) = finish_startup(pending, &s, &m) {
+                                    eprintln!("could not resync after reconnecting: {err}");
+                                }
+                            });
+                        }
+                        link_lost = false;
+                    }
+                    LinkState::Down(_) => link_lost = true,
+                }
+            }
             ChannelType::Request(r) => match r.action.as_ref() {
                 "info" => mqt
                     .publish_info(mqtt::Info {
@@ -203,6 +556,11 @@ fn main() -> Result<()> {
                         region: conf.region.clone(),
                         country: conf.country.clone(),
                         rapid_weather: true,
+                        forecast: fcst
+                            .lock()
+                            .unwrap()
+                            .as_ref()
+                            .map(|f| f.info(conf.mqtt.units)),
                     })
                     .unwrap(),
                 "rapid-weather" => {
@@ -210,95 +568,132 @@ fn main() -> Result<()> {
                     rapid_due = Instant::now() + Duration::from_secs(60);
                     rapid_update_due = Instant::now();
                 }
+                "auto-enable" | "auto-disable" | "autos" => {
+                    let ops = autos_ops(&snsrs.lock().unwrap(), &r);
+                    match ops {
+                        Ok(ops) => {
+                            let (c, s, m) = (cmd.clone(), snsrs.clone(), mqt.clone());
+                            // This waits on the station, which needs this thread to be free
+                            thread::spawn(move || {
+                                if let Err(err) = set_autos(&c, &s, &m, ops) {
+                                    eprintln!("could not change autos: {err}");
+                                }
+                            });
+                        }
+                        Err(err) => eprintln!("{err}"),
+                    }
+                }
+                "stats" => {
+                    let stats = stats(
+                        &snsrs.lock().unwrap(),
+                        &r,
+                        conf.history.window,
+                        conf.mqtt.units,
+                    );
+                    if let Err(err) = mqt.publish_stats(stats) {
+                        eprintln!("could not publish stats: {err}");
+                    }
+                }
+                "reset" => {
+                    let (c, s, m) = (cmd.clone(), snsrs.clone(), mqt.clone());
+                    thread::spawn(move || {
+                        if let Err(err) = reset(&c, &s, &m) {
+                            eprintln!("could not reset the station: {err}");
+                        }
+                    });
+                }
                 _ => {}
             },
         }
     });
 
-    let (tx, rx) = mpsc::channel();
-
-    commands.command(set_clock_code());
-    commands.command_guarentee(
-        request_all_sensors_code(),
-        tx.clone(),
-        Duration::from_secs(1),
-    );
-    commands.command_guarentee(request_autos_code(), tx.clone(), Duration::from_secs(1));
-    rx.recv()?;
-    rx.recv()?;
-
-    fn map_sensor(val: Option<&Sensor>) -> Vec<SensorValue> {
-        val.into_iter()
-            .map(|v| SensorValue {
-                unit: v.unit.to_string(),
-                value: v.value,
-            })
-            .collect()
-    }
+    // Nothing can be published until the station has told us its sensors
+    finish_startup(
+        startup(
+            &commands,
+            RetryPolicy::new(Duration::from_secs(1)).forever(),
+        ),
+        &sensors,
+        &mqtt,
+    )?;
 
+    let mut rain = RainGauge::load(&conf.state_dir);
+    let mut extremes = Extremes::load(&conf.state_dir);
     let mut last_time_set = chrono::offset::Local::now();
+    let mut last_discovery = Instant::now();
+    let discovery_interval = Duration::from_secs_f32(conf.polling.discovery_interval);
 
     loop {
         let is_rapid = on_update.recv().unwrap();
-        get_updates(sensors.clone(), commands.clone())?;
+        get_updates(
+            sensors.clone(),
+            commands.clone(),
+            Duration::from_secs_f32(conf.polling.auto_timeout),
+        )?;
+
+        if last_discovery.elapsed() >= discovery_interval {
+            last_discovery = Instant::now();
+            if let Err(err) = discover(&sensors, &commands, &mqtt) {
+                eprintln!("could not discover sensors: {err}");
+            }
+        }
 
         if (chrono::offset::Local::now() - last_time_set).num_hours() >= 1 {
             last_time_set = chrono::offset::Local::now();
-            commands.command(set_clock_code());
+            commands.command(StationCommand::set_clock_now().into());
         }
 
         let s = sensors.lock().unwrap();
 
         println!("{s:?}");
 
-        let temp = s.get("temperature");
-        let humi = s.get("humidity");
-
-        // https://www.omnicalculator.com/physics/dew-point#how-to-calculate-dew-point-how-to-calculate-relative-humidity
-        let dewp = if let Some(temp) = temp {
-            if let Some(humi) = humi {
-                const B: f32 = 243.04;
-                const A: f32 = 17.625;
-                let t = temp.value;
-                let rh = humi.value / 100.0;
-                let a = rh.ln() + (A * t / (B + t));
-                vec![SensorValue {
-                    value: (B * a) / (A - a),
-                    unit: temp.unit.to_string(),
-                }]
-            } else {
-                vec![]
-            }
-        } else {
-            vec![]
-        };
-
-        let update = Update {
-            time: chrono::Local::now().to_rfc3339(),
-            id: conf.mqtt.id.to_owned(),
-            sensors: HashMap::from_iter(
-                [
-                    ("winddir", map_sensor(s.get("wind heading"))),
-                    ("windspd", map_sensor(s.get("wind speed"))),
-                    ("windgustspd-2m", map_sensor(s.get("gust 2m wind speed"))),
-                    ("windgustdir-2m", map_sensor(s.get("gust 2m wind heading"))),
-                    ("windspd-avg2m", map_sensor(s.get("avg 2m wind speed"))),
-                    ("winddir-avg2m", map_sensor(s.get("avg 2m wind heading"))),
-                    ("windspd-avg10m", map_sensor(s.get("avg 10m wind speed"))),
-                    ("winddir-avg10m", map_sensor(s.get("avg 10m wind heading"))),
-                    ("humidity", map_sensor(s.get("humidity"))),
-                    ("temp", map_sensor(s.get("temperature"))),
-                    ("rain-1h", map_sensor(s.get("rain hour"))),
-                    ("dailyrain", map_sensor(s.get("rain day"))),
-                    ("barom", map_sensor(s.get("pressure"))),
-                    ("uv", map_sensor(s.get("uv"))),
-                    ("dewpoint", dewp),
-                ]
-                .into_iter()
-                .map(|(k, v)| (k.into(), v)),
-            ),
+        let units = match is_rapid {
+            true => conf.mqtt.rapid_units.unwrap_or(conf.mqtt.units),
+            false => conf.mqtt.units,
         };
-
+        let mut builder = ReportBuilder::new(&conf.quality, units);
+        builder.sensors(&s, &conf.sensors);
+        wind::publish(&mut builder, &conf.wind, &s, &conf.sensors, &conf.quality);
+        rain.publish(
+            &mut builder,
+            &conf.rain,
+            &conf.day,
+            &s,
+            &conf.sensors,
+            &conf.quality,
+        );
+
+        let inputs = derived::Inputs::lookup(&s, &conf.sensors, &conf.quality);
+        derived::publish(
+            &mut builder,
+            &conf.derived,
+            &inputs,
+            conf.elevation as f32,
+            conf.latitude as f32,
+        );
+
+        let f = Forecast::new(
+            &s,
+            &conf.sensors,
+            &conf.quality,
+            conf.elevation as f32,
+            conf.latitude as f32,
+        );
+        forecast::publish(&mut builder, &conf.derived, f.as_ref());
+        *latest_forecast.lock().unwrap() = f;
+
+        let update = builder.build(conf.mqtt.id.to_owned());
         mqtt.publish_update(update, is_rapid)?;
+
+        if conf.extremes.enabled {
+            let rain_rate = conf.rain.enabled.then(|| rain.rate(&conf.rain));
+            extremes.update(&conf.day, &s, &conf.sensors, &conf.quality, rain_rate);
+            // The extremes only need to keep up with the regular updates
+            if !is_rapid {
+                if let Some(report) = extremes.report(conf.mqtt.units, &conf.day) {
+                    mqtt.publish_extremes(report)?;
+                }
+            }
+        }
     }
 }
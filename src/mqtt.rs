@@ -5,6 +5,8 @@ use std::{collections::HashMap, sync::mpsc};
 
 use paho_mqtt::{Client, Message};
 
+use crate::sensor::Quality;
+
 pub struct Mqtt {
     client: Client,
     id: String,
@@ -66,6 +68,59 @@ impl Mqtt {
         Ok(self.client.publish(msg)?)
     }
 
+    /// Publish the state of the station link to '/station/link/{id}'
+    ///
+    /// The message is retained so that new subscribers know whether the
+    /// station is reachable.
+    pub fn publish_link(&self, link: Link) -> Result<()> {
+        let msg = Message::new_retained(
+            format!("/station/link/{id}", id = self.id),
+            serde_json::to_string(&link)?,
+            1,
+        );
+        Ok(self.client.publish(msg)?)
+    }
+
+    /// Publish which sensors are reported automatically to '/station/autos/{id}'
+    pub fn publish_autos(&self, autos: Autos) -> Result<()> {
+        let msg = Message::new_retained(
+            format!("/station/autos/{id}", id = self.id),
+            serde_json::to_string(&autos)?,
+            1,
+        );
+        Ok(self.client.publish(msg)?)
+    }
+
+    /// Publish a change to the set of sensors to '/station/sensors/{id}'
+    pub fn publish_sensor_change(&self, change: SensorChange) -> Result<()> {
+        let msg = Message::new(
+            format!("/station/sensors/{id}", id = self.id),
+            serde_json::to_string(&change)?,
+            1,
+        );
+        Ok(self.client.publish(msg)?)
+    }
+
+    /// Publish statistics of the sensors to '/station/stats/{id}'
+    pub fn publish_stats(&self, stats: Stats) -> Result<()> {
+        let msg = Message::new(
+            format!("/station/stats/{id}", id = self.id),
+            serde_json::to_string(&stats)?,
+            1,
+        );
+        Ok(self.client.publish(msg)?)
+    }
+
+    /// Publish the extremes of today and yesterday to '/station/extremes/{id}'
+    pub fn publish_extremes(&self, extremes: Extremes) -> Result<()> {
+        let msg = Message::new(
+            format!("/station/extremes/{id}", id = self.id),
+            serde_json::to_string(&extremes)?,
+            1,
+        );
+        Ok(self.client.publish(msg)?)
+    }
+
     /// Publish info about the weather station to '/station/info/{id}'
     pub fn publish_info(&self, info: Info) -> Result<()> {
         let msg = Message::new(
@@ -79,8 +134,20 @@ impl Mqtt {
 
 #[derive(Debug, Serialize)]
 pub struct SensorValue {
-    pub unit: String,
-    pub value: f32,
+    /// Missing sensors have no unit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub value: Option<f32>,
+    /// The value before calibration, when calibration changed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<f32>,
+    pub quality: Quality,
+    /// When the reading was taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    /// Milliseconds between the reading and the update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -88,6 +155,26 @@ pub struct Update {
     pub time: String,
     pub id: String,
     pub sensors: HashMap<String, Vec<SensorValue>>,
+    /// Readings that aren't numbers, such as the forecast
+    ///
+    /// Missing ones are null when a missing marker is configured.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub text: HashMap<String, Option<String>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Link {
+    pub time: String,
+    pub state: LinkStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -105,9 +192,111 @@ pub struct Info {
     pub country: String,
     #[serde(rename = "rapid-weather")]
     pub rapid_weather: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forecast: Option<InfoForecast>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InfoForecast {
+    /// Station pressure change over the last 3 hours
+    pub tendency: f32,
+    #[serde(rename = "tendency-unit")]
+    pub tendency_unit: String,
+    /// WMO pressure tendency code
+    #[serde(rename = "tendency-code")]
+    pub tendency_code: u8,
+    pub trend: String,
+    /// Zambretti forecast letter
+    pub code: char,
+    pub forecast: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SensorChange {
+    pub time: String,
+    pub event: SensorChangeKind,
+    pub sensor: u8,
+    pub name: String,
+    pub unit: String,
+    #[serde(rename = "previous-name", skip_serializing_if = "Option::is_none")]
+    pub previous_name: Option<String>,
+    #[serde(rename = "previous-unit", skip_serializing_if = "Option::is_none")]
+    pub previous_unit: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SensorStats {
+    pub unit: String,
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub stddev: f32,
+}
+
+/// Statistics of sensors, in the units of the weather updates
+///
+/// Rapid updates may use other units, but these always follow `mqtt.units`.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub time: String,
+    /// Seconds covered by the statistics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<f32>,
+    pub sensors: HashMap<String, SensorStats>,
+    /// Why the request could not be answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Extreme {
+    pub value: f32,
+    pub unit: String,
+    /// When the extreme happened
+    pub time: String,
+    /// Wind heading of a gust
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DayExtremes {
+    pub date: String,
+    #[serde(flatten)]
+    pub extremes: HashMap<String, Extreme>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Extremes {
+    pub time: String,
+    pub today: DayExtremes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yesterday: Option<DayExtremes>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Autos {
+    pub time: String,
+    pub sensors: HashMap<String, bool>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Request {
     pub action: String,
+    /// The sensor name for 'auto-enable', 'auto-disable' and 'stats'
+    pub sensor: Option<String>,
+    /// Every sensor that should be reported automatically for 'autos', or
+    /// the sensors to get 'stats' of
+    pub sensors: Option<Vec<String>>,
+    /// Seconds of history for 'stats'
+    pub window: Option<f32>,
 }
@@ -0,0 +1,667 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use ordoo::or_do;
+use scode_rs::{CodeSend, ParamSend, ParamValue};
+use std::fmt::Display;
+
+use crate::station::Rule;
+
+/// The station counts days from the start of 1984
+fn epoch() -> NaiveDateTime {
+    NaiveDate::from_yo_opt(1984, 1)
+        .unwrap()
+        .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+}
+
+/// A code from the station that could not be understood
+#[derive(Debug, Clone)]
+pub enum ProtocolError {
+    /// A required parameter was not sent
+    MissingParam { code: (u8, u8), param: u8 },
+    /// A parameter had a value of the wrong type or range
+    BadParam { code: (u8, u8), param: u8 },
+    /// A parameter that has no meaning for the code
+    UnexpectedParam { code: (u8, u8), param: u8 },
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (what, (letter, number), param) = match self {
+            Self::MissingParam { code, param } => ("missing parameter", code, param),
+            Self::BadParam { code, param } => ("bad parameter", code, param),
+            Self::UnexpectedParam { code, param } => ("unexpected parameter", code, param),
+        };
+        f.write_fmt(format_args!(
+            "{}{number}: {what} {}",
+            *letter as char, *param as char
+        ))
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A sensor reading, `S<id> [N"name"] [U"unit"] V<value>`
+///
+/// The name and unit are only sent when the full sensor was requested.
+#[derive(Debug, Clone)]
+pub struct SensorReport {
+    pub id: u8,
+    pub name: Option<String>,
+    pub unit: Option<String>,
+    pub value: f32,
+}
+
+/// A set of sensor ids
+///
+/// Covers every possible id, so it is wider than any integer parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SensorMask([u64; 4]);
+
+impl SensorMask {
+    /// A mask of the first 64 sensors
+    pub fn from_bits(bits: u64) -> Self {
+        Self([bits, 0, 0, 0])
+    }
+
+    /// A mask of any width where bit 0 of byte 0 is sensor 0
+    pub fn from_le_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > 32 {
+            return None;
+        }
+        let mut mask = Self::default();
+        for (i, byte) in bytes.iter().enumerate() {
+            mask.0[i / 8] |= (*byte as u64) << ((i % 8) * 8);
+        }
+        Some(mask)
+    }
+
+    pub fn contains(&self, id: u8) -> bool {
+        self.0[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    pub fn insert(&mut self, id: u8) {
+        self.0[id as usize / 64] |= 1 << (id % 64);
+    }
+
+    pub fn remove(&mut self, id: u8) {
+        self.0[id as usize / 64] &= !(1 << (id % 64));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|id| self.contains(*id))
+    }
+}
+
+/// A single parameter of an `M102` auto reporting message
+#[derive(Debug, Clone, Copy)]
+pub enum AutosOp {
+    /// `E<id>`: the sensor is reported automatically
+    Enable(u8),
+    /// `D<id>`: the sensor is not reported automatically
+    Disable(u8),
+    /// `V<mask>`: exactly these sensors are reported automatically
+    ///
+    /// The mask is either an integer, or a little endian byte string for
+    /// stations with more than 64 sensors.
+    Set(SensorMask),
+}
+
+/// The parameters of an `M102` message in the order they were sent
+#[derive(Debug, Clone)]
+pub struct AutosUpdate {
+    pub ops: Vec<AutosOp>,
+    /// Parameters that were skipped because they made no sense
+    pub diagnostics: Vec<ProtocolError>,
+}
+
+impl AutosUpdate {
+    /// The sensors that are reported automatically
+    pub fn mask(&self) -> SensorMask {
+        let mut mask = SensorMask::default();
+        for op in &self.ops {
+            match *op {
+                AutosOp::Enable(id) => mask.insert(id),
+                AutosOp::Disable(id) => mask.remove(id),
+                AutosOp::Set(m) => mask = m,
+            }
+        }
+        mask
+    }
+}
+
+/// Everything the station can send us
+#[derive(Debug, Clone)]
+pub enum StationMessage {
+    /// `S<id>`: a sensor reading
+    SensorReport(SensorReport),
+    /// `O1 <letter><number>`: a command was accepted
+    Ack { letter: u8, number: u8 },
+    /// `M102`: which sensors are reported automatically
+    AutosMask(AutosUpdate),
+    /// `M10 D<days> T<ms>`: the station's clock, in days since 1984 and
+    /// milliseconds since midnight
+    ClockReport { days: i64, ms: i64 },
+    /// Any code without a meaning to us
+    Unknown(CodeSend),
+}
+
+fn find<'a>(code: &'a CodeSend, param: u8) -> Result<&'a ParamSend, ProtocolError> {
+    code.find(param).ok_or(ProtocolError::MissingParam {
+        code: (code.letter, code.number),
+        param,
+    })
+}
+
+fn bad(code: &CodeSend, param: u8) -> ProtocolError {
+    ProtocolError::BadParam {
+        code: (code.letter, code.number),
+        param,
+    }
+}
+
+fn param_f32(code: &CodeSend, param: u8) -> Result<f32, ProtocolError> {
+    let p = find(code, param)?;
+    // Values may also be sent as strings
+    Ok(or_do!(
+        p.value.as_borrowed().cast_f32(),
+        v => or_do!(
+            std::str::from_utf8(v).ok().and_then(|v| v.parse().ok()),
+            return Err(bad(code, param))
+        )
+    ))
+}
+
+fn param_i64(code: &CodeSend, param: u8) -> Result<i64, ProtocolError> {
+    find(code, param)?
+        .value
+        .as_borrowed()
+        .cast_i64()
+        .map_err(|_| bad(code, param))
+}
+
+fn param_str(code: &CodeSend, param: u8) -> Option<String> {
+    let bytes = code.find(param)?.value.as_borrowed().cast_bytes();
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The `<letter><number>` a command answer refers to
+fn answered(code: &CodeSend) -> Result<(u8, u8), ProtocolError> {
+    let p = or_do!(
+        code.params.first(),
+        return Err(ProtocolError::MissingParam {
+            code: (code.letter, code.number),
+            param: b'?',
+        })
+    );
+    let number = p
+        .value
+        .as_borrowed()
+        .cast_u8()
+        .map_err(|_| bad(code, p.letter))?;
+    Ok((p.letter, number))
+}
+
+impl StationMessage {
+    pub fn parse(code: &CodeSend) -> Result<Self, ProtocolError> {
+        Ok(match (code.letter, code.number) {
+            (b'S', id) => Self::SensorReport(SensorReport {
+                id,
+                name: param_str(code, b'N'),
+                unit: param_str(code, b'U'),
+                value: param_f32(code, b'V')?,
+            }),
+            (b'O', 1) => {
+                let (letter, number) = answered(code)?;
+                Self::Ack { letter, number }
+            }
+            (b'M', 102) => {
+                // Be lenient here, newer firmware may send parameters we don't know
+                let mut ops = Vec::with_capacity(code.params.len());
+                let mut diagnostics = Vec::new();
+                for p in &code.params {
+                    let op = match p.letter {
+                        b'E' => p.value.as_borrowed().cast_u8().ok().map(AutosOp::Enable),
+                        b'D' => p.value.as_borrowed().cast_u8().ok().map(AutosOp::Disable),
+                        b'V' => match p.value.as_borrowed().cast_i64() {
+                            Ok(bits) => Some(AutosOp::Set(SensorMask::from_bits(bits as u64))),
+                            Err(_) => {
+                                let bytes = p.value.as_borrowed().cast_bytes();
+                                SensorMask::from_le_bytes(&bytes).map(AutosOp::Set)
+                            }
+                        },
+                        param => {
+                            diagnostics.push(ProtocolError::UnexpectedParam {
+                                code: (code.letter, code.number),
+                                param,
+                            });
+                            continue;
+                        }
+                    };
+                    match op {
+                        Some(op) => ops.push(op),
+                        None => diagnostics.push(bad(code, p.letter)),
+                    }
+                }
+                Self::AutosMask(AutosUpdate { ops, diagnostics })
+            }
+            (b'M', 10) => Self::ClockReport {
+                days: param_i64(code, b'D')?,
+                ms: param_i64(code, b'T')?,
+            },
+            _ => Self::Unknown(code.clone()),
+        })
+    }
+}
+
+/// Everything we can ask of the station
+#[derive(Debug, Clone)]
+pub enum StationCommand {
+    /// `M1`: report every sensor with its name and unit
+    RequestAllSensors,
+    /// `S<id>`: report a sensor, `R"V"` limits the report to its value
+    RequestSensor { id: u8, only_value: bool },
+    /// `M102`: report which sensors are reported automatically
+    RequestAutos,
+    /// `M10 D<days> T<ms>`: set the station's clock
+    SetClock(DateTime<Local>),
+    /// `M102`: change which sensors are reported automatically
+    SetAutos(Vec<AutosOp>),
+    /// `M20`: restart the station
+    Reset,
+}
+
+fn autos_params(op: AutosOp) -> Vec<ParamSend> {
+    let param = |letter, id: u8| ParamSend {
+        letter,
+        value: (id as i32).into(),
+    };
+    match op {
+        AutosOp::Enable(id) => vec![param(b'E', id)],
+        AutosOp::Disable(id) => vec![param(b'D', id)],
+        AutosOp::Set(mask) => {
+            if mask.iter().all(|id| id < 31) {
+                vec![ParamSend {
+                    letter: b'V',
+                    value: (mask.0[0] as i32).into(),
+                }]
+            } else {
+                // Too wide for an integer, so clear everything and enable one by one
+                let mut params = vec![ParamSend {
+                    letter: b'V',
+                    value: 0i32.into(),
+                }];
+                params.extend(mask.iter().map(|id| param(b'E', id)));
+                params
+            }
+        }
+    }
+}
+
+impl StationCommand {
+    /// Set the station's clock to the current time
+    pub fn set_clock_now() -> Self {
+        Self::SetClock(Local::now())
+    }
+}
+
+impl From<StationCommand> for CodeSend {
+    fn from(value: StationCommand) -> Self {
+        let (letter, number, params) = match value {
+            StationCommand::RequestAllSensors => (b'M', 1, vec![]),
+            StationCommand::RequestSensor { id, only_value } => (
+                b'S',
+                id,
+                match only_value {
+                    true => vec![ParamSend {
+                        letter: b'R',
+                        value: ParamValue::str("V"),
+                    }],
+                    false => vec![],
+                },
+            ),
+            StationCommand::RequestAutos => (b'M', 102, vec![]),
+            StationCommand::SetClock(time) => {
+                let epoch = epoch();
+                let days = (time.date_naive() - epoch.date()).num_days();
+                let ms = (time.time() - epoch.time()).num_milliseconds();
+                (
+                    b'M',
+                    10,
+                    vec![
+                        ParamSend {
+                            letter: b'D',
+                            value: (days as i32).into(),
+                        },
+                        ParamSend {
+                            letter: b'T',
+                            value: (ms as i32).into(),
+                        },
+                    ],
+                )
+            }
+            StationCommand::SetAutos(ops) => {
+                (b'M', 102, ops.into_iter().flat_map(autos_params).collect())
+            }
+            StationCommand::Reset => (b'M', 20, vec![]),
+        };
+        CodeSend {
+            letter,
+            number,
+            params,
+        }
+    }
+}
+
+/// Log any code that no other callback wanted
+///
+/// This should be registered last.
+pub fn unhandled_callback() -> (Rule, impl Fn(&CodeSend) -> bool) {
+    (Rule::default(), |code| {
+        match StationMessage::parse(code) {
+            Ok(StationMessage::ClockReport { days, ms }) => {
+                let station = epoch() + Duration::days(days) + Duration::milliseconds(ms);
+                let drift = station - Local::now().naive_local();
+                println!(
+                    "station clock is {station}, {:.1}s off",
+                    drift.num_milliseconds() as f64 / 1000.0
+                );
+            }
+            Ok(StationMessage::Unknown(code)) => {
+                eprintln!("unhandled code {}{}", code.letter as char, code.number)
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("{err}"),
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use scode_rs::Code;
+
+    pub(crate) fn str_param(letter: u8, value: &str) -> ParamSend {
+        ParamSend {
+            letter,
+            value: ParamValue::str(value),
+        }
+    }
+
+    pub(crate) fn int_param(letter: u8, value: i32) -> ParamSend {
+        ParamSend {
+            letter,
+            value: value.into(),
+        }
+    }
+
+    pub(crate) fn code(letter: u8, number: u8, params: Vec<ParamSend>) -> CodeSend {
+        CodeSend {
+            letter,
+            number,
+            params,
+        }
+    }
+
+    /// A sensor report with its name and unit, as sent in reply to `M1`
+    pub(crate) fn report(id: u8, name: &str, unit: &str, value: f32) -> CodeSend {
+        code(
+            b'S',
+            id,
+            vec![
+                str_param(b'N', name),
+                str_param(b'U', unit),
+                str_param(b'V', &format!("{value:.2}")),
+            ],
+        )
+    }
+
+    /// A sensor report of only the value, as pushed by an auto reported sensor
+    pub(crate) fn value_report(id: u8, value: f32) -> CodeSend {
+        code(b'S', id, vec![str_param(b'V', &format!("{value:.2}"))])
+    }
+
+    pub(crate) fn ack(letter: u8, number: u8) -> CodeSend {
+        code(b'O', 1, vec![int_param(letter, number as i32)])
+    }
+
+    /// The bytes the station would send for `codes`
+    pub(crate) fn encode(codes: impl IntoIterator<Item = CodeSend>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for code in codes {
+            let code = Code::try_from(code).unwrap_or_else(|err| panic!("{err}"));
+            let mut binary = code.dump_binary_vec().unwrap_or_else(|err| panic!("{err}"));
+            bytes.append(&mut binary);
+        }
+        bytes
+    }
+
+    #[test]
+    fn parse_sensor_report() {
+        let report = match StationMessage::parse(&report(3, "humidity", "%", 45.5)) {
+            Ok(StationMessage::SensorReport(report)) => report,
+            other => panic!("not a sensor report: {other:?}"),
+        };
+        assert_eq!(report.id, 3);
+        assert_eq!(report.name.as_deref(), Some("humidity"));
+        assert_eq!(report.unit.as_deref(), Some("%"));
+        assert_eq!(report.value, 45.5);
+
+        let report = match StationMessage::parse(&code(b'S', 4, vec![int_param(b'V', 12)])) {
+            Ok(StationMessage::SensorReport(report)) => report,
+            other => panic!("not a sensor report: {other:?}"),
+        };
+        assert_eq!(report.id, 4);
+        assert_eq!(report.name, None);
+        assert_eq!(report.unit, None);
+        assert_eq!(report.value, 12.0);
+    }
+
+    #[test]
+    fn parse_bad_sensor_report() {
+        assert!(matches!(
+            StationMessage::parse(&code(b'S', 3, vec![str_param(b'N', "uv")])),
+            Err(ProtocolError::MissingParam {
+                code: (b'S', 3),
+                param: b'V'
+            })
+        ));
+        assert!(matches!(
+            StationMessage::parse(&code(b'S', 3, vec![str_param(b'V', "lots")])),
+            Err(ProtocolError::BadParam {
+                code: (b'S', 3),
+                param: b'V'
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_ack() {
+        assert!(matches!(
+            StationMessage::parse(&ack(b'M', 102)),
+            Ok(StationMessage::Ack {
+                letter: b'M',
+                number: 102
+            })
+        ));
+        assert!(matches!(
+            StationMessage::parse(&code(b'O', 1, vec![])),
+            Err(ProtocolError::MissingParam {
+                code: (b'O', 1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            StationMessage::parse(&code(b'O', 1, vec![str_param(b'S', "three")])),
+            Err(ProtocolError::BadParam {
+                code: (b'O', 1),
+                param: b'S'
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_autos() {
+        let autos = code(
+            b'M',
+            102,
+            vec![
+                int_param(b'V', 0b1010),
+                int_param(b'E', 5),
+                int_param(b'D', 1),
+                int_param(b'X', 7),
+            ],
+        );
+        let update = match StationMessage::parse(&autos) {
+            Ok(StationMessage::AutosMask(update)) => update,
+            other => panic!("not an autos mask: {other:?}"),
+        };
+        assert_eq!(update.mask().iter().collect::<Vec<_>>(), vec![3, 5]);
+        assert!(matches!(
+            update.diagnostics.as_slice(),
+            [ProtocolError::UnexpectedParam {
+                code: (b'M', 102),
+                param: b'X'
+            }]
+        ));
+    }
+
+    #[test]
+    fn parse_wide_autos() {
+        // Sensors 0 and 65 as a little endian byte string
+        let mask = "\x01\0\0\0\0\0\0\0\x02";
+        let update = match StationMessage::parse(&code(b'M', 102, vec![str_param(b'V', mask)])) {
+            Ok(StationMessage::AutosMask(update)) => update,
+            other => panic!("not an autos mask: {other:?}"),
+        };
+        assert_eq!(update.mask().iter().collect::<Vec<_>>(), vec![0, 65]);
+        assert!(update.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn parse_clock() {
+        let clock = code(
+            b'M',
+            10,
+            vec![int_param(b'D', 14000), int_param(b'T', 5000)],
+        );
+        assert!(matches!(
+            StationMessage::parse(&clock),
+            Ok(StationMessage::ClockReport {
+                days: 14000,
+                ms: 5000
+            })
+        ));
+        assert!(matches!(
+            StationMessage::parse(&code(b'M', 10, vec![int_param(b'D', 14000)])),
+            Err(ProtocolError::MissingParam {
+                code: (b'M', 10),
+                param: b'T'
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_unknown() {
+        for unknown in [
+            code(b'M', 99, vec![]),
+            code(b'O', 2, vec![int_param(b'S', 3)]),
+        ] {
+            assert!(matches!(
+                StationMessage::parse(&unknown),
+                Ok(StationMessage::Unknown(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn encode_request_sensor() {
+        let full = CodeSend::from(StationCommand::RequestSensor {
+            id: 7,
+            only_value: false,
+        });
+        assert_eq!((full.letter, full.number), (b'S', 7));
+        assert!(full.params.is_empty());
+
+        let value = CodeSend::from(StationCommand::RequestSensor {
+            id: 7,
+            only_value: true,
+        });
+        assert_eq!(param_str(&value, b'R').as_deref(), Some("V"));
+    }
+
+    #[test]
+    fn encode_clock() {
+        let time = Local
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(1984, 1, 3)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 2)
+                    .unwrap(),
+            )
+            .unwrap();
+        let clock = CodeSend::from(StationCommand::SetClock(time));
+        assert_eq!((clock.letter, clock.number), (b'M', 10));
+        assert_eq!(param_i64(&clock, b'D').unwrap(), 2);
+        assert_eq!(param_i64(&clock, b'T').unwrap(), 2000);
+
+        // The station answers in the same format
+        assert!(matches!(
+            StationMessage::parse(&clock),
+            Ok(StationMessage::ClockReport { days: 2, ms: 2000 })
+        ));
+    }
+
+    fn round_trip(mask: SensorMask) -> SensorMask {
+        let code = CodeSend::from(StationCommand::SetAutos(vec![AutosOp::Set(mask)]));
+        match StationMessage::parse(&code) {
+            Ok(StationMessage::AutosMask(update)) => update.mask(),
+            other => panic!("not an autos mask: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn encode_autos() {
+        let mut mask = SensorMask::default();
+        mask.insert(0);
+        mask.insert(12);
+        let code = CodeSend::from(StationCommand::SetAutos(vec![AutosOp::Set(mask)]));
+        assert_eq!(code.params.len(), 1);
+        assert_eq!(param_i64(&code, b'V').unwrap(), 1 << 12 | 1);
+        assert_eq!(round_trip(mask), mask);
+
+        let code = CodeSend::from(StationCommand::SetAutos(vec![
+            AutosOp::Enable(4),
+            AutosOp::Disable(2),
+        ]));
+        let letters: Vec<_> = code.params.iter().map(|p| p.letter).collect();
+        assert_eq!(letters, vec![b'E', b'D']);
+    }
+
+    #[test]
+    fn encode_wide_autos() {
+        let mut mask = SensorMask::default();
+        mask.insert(3);
+        mask.insert(64);
+        mask.insert(200);
+        let code = CodeSend::from(StationCommand::SetAutos(vec![AutosOp::Set(mask)]));
+        // Cleared first, then every sensor enabled on its own
+        let params: Vec<_> = code
+            .params
+            .iter()
+            .map(|p| (p.letter, p.value.as_borrowed().cast_i64().unwrap()))
+            .collect();
+        assert_eq!(params, vec![(b'V', 0), (b'E', 3), (b'E', 64), (b'E', 200)]);
+        assert_eq!(round_trip(mask), mask);
+    }
+
+    #[test]
+    fn mask_from_bytes() {
+        assert_eq!(SensorMask::from_le_bytes(&[]), Some(SensorMask::default()));
+        assert_eq!(SensorMask::from_le_bytes(&[0; 33]), None);
+        let mut bytes = [0; 32];
+        bytes[31] = 0x80;
+        let mask = SensorMask::from_le_bytes(&bytes).unwrap();
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![255]);
+    }
+}
@@ -0,0 +1,285 @@
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    conf::{DayConf, QualityConf, RainConf, SensorsConf},
+    report::{self, ReportBuilder},
+    sensor::{Quality, Sensors},
+    state,
+    units::{Quantity, Unit},
+};
+
+/// Seconds of rain kept for the 24 hour total
+const RECENT: i64 = 24 * 60 * 60;
+
+/// Every key published by the gauge
+const KEYS: [&str; 6] = [
+    "rainrate",
+    "rain-24h",
+    "rain-today",
+    "rain-storm",
+    "rain-month",
+    "rain-year",
+];
+
+/// Rain totals in mm
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Totals {
+    /// The last reading of the station's counter
+    counter: Option<f32>,
+    /// The weather day as year, month and day
+    date: Option<(i32, u32, u32)>,
+    today: f32,
+    month: f32,
+    year: f32,
+    storm: f32,
+    /// Unix time of the last rain
+    last_rain: Option<i64>,
+    /// Rain by unix time, over the last 24 hours
+    recent: VecDeque<(i64, f32)>,
+}
+
+impl Totals {
+    /// Start over the totals of every period that `date` is not in
+    ///
+    /// Returns whether the day changed.
+    fn roll(&mut self, date: NaiveDate) -> bool {
+        let date = (date.year(), date.month(), date.day());
+        let last = self.date.replace(date);
+        if last == Some(date) {
+            return false;
+        }
+        self.today = 0.0;
+        if last.map(|(y, m, _)| (y, m)) != Some((date.0, date.1)) {
+            self.month = 0.0;
+        }
+        if last.map(|(y, _, _)| y) != Some(date.0) {
+            self.year = 0.0;
+        }
+        true
+    }
+
+    /// The rain since the last reading of the station's counter
+    fn count(&mut self, counter: f32) -> f32 {
+        let rain = match self.counter {
+            // Nothing to compare the first reading to
+            None => 0.0,
+            Some(last) if counter >= last => counter - last,
+            // The station reset its counter, either at its midnight or by
+            // rebooting, so all of the counter is new rain
+            Some(_) => counter,
+        };
+        self.counter = Some(counter);
+        rain
+    }
+
+    /// Start a new storm once it hasn't rained for `gap` seconds
+    fn end_storm(&mut self, now: i64, gap: i64) {
+        if self.last_rain.map_or(true, |last| now - last > gap) {
+            self.storm = 0.0;
+        }
+    }
+
+    fn add(&mut self, now: i64, rain: f32) {
+        self.today += rain;
+        self.month += rain;
+        self.year += rain;
+        self.storm += rain;
+        self.last_rain = Some(now);
+        self.recent.push_back((now, rain));
+    }
+
+    /// Rain since `since`
+    fn since(&self, since: i64) -> f32 {
+        self.recent
+            .iter()
+            .filter(|(time, _)| *time > since)
+            .map(|(_, rain)| rain)
+            .sum()
+    }
+}
+
+/// Rain totals kept on the host
+///
+/// The station only counts rain for its own day, so the totals are
+/// accumulated here from the changes of its counter.
+pub struct RainGauge {
+    path: PathBuf,
+    totals: Totals,
+}
+
+impl RainGauge {
+    /// Pick up the totals from before the last restart
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join("rain.json");
+        Self {
+            totals: state::load(&path),
+            path,
+        }
+    }
+
+    /// The rain rate in mm/h
+    pub fn rate(&self, conf: &RainConf) -> f32 {
+        let window = conf.rate_window * 60.0;
+        let since = Utc::now().timestamp() - window as i64;
+        self.totals.since(since) * 3600.0 / window
+    }
+
+    /// Count the rain since the last update and add the totals to it
+    pub fn publish(
+        &mut self,
+        builder: &mut ReportBuilder,
+        conf: &RainConf,
+        day: &DayConf,
+        sensors: &Sensors,
+        sensors_conf: &SensorsConf,
+        quality: &QualityConf,
+    ) {
+        if !conf.enabled {
+            return;
+        }
+        let sensor = match report::lookup(sensors, sensors_conf, &conf.counter)
+            .into_iter()
+            .next()
+        {
+            Some(sensor) => sensor,
+            None => {
+                for key in KEYS {
+                    builder.missing(key);
+                }
+                return;
+            }
+        };
+        let unit = Unit::parse(&sensor.unit)
+            .filter(|u| u.quantity() == Quantity::Length)
+            .unwrap_or(Unit::Millimeters);
+        let quality = sensor.quality(quality);
+
+        let time = Utc::now();
+        let now = time.timestamp();
+        let totals = &mut self.totals;
+        let mut changed = totals.roll(day.date(time));
+        totals.end_storm(now, (conf.storm_gap * 3600.0) as i64);
+        while let Some((time, _)) = totals.recent.front() {
+            if now - time <= RECENT {
+                break;
+            }
+            totals.recent.pop_front();
+        }
+        // Out of range counts are most likely glitches rather than rain
+        if quality == Quality::Fresh {
+            let counter = unit.convert(sensor.value, Unit::Millimeters).unwrap();
+            let last = totals.counter;
+            let rain = totals.count(counter);
+            if rain > 0.0 {
+                totals.add(now, rain);
+            }
+            changed |= last != totals.counter;
+        }
+        if changed {
+            if let Err(err) = state::save(&self.path, &self.totals) {
+                eprintln!("could not save the rain totals: {err}");
+            }
+        }
+
+        let totals = &self.totals;
+        let rate_unit = match unit {
+            Unit::Inches => Unit::InchesPerHour,
+            _ => Unit::MillimetersPerHour,
+        };
+        let measured = sensor.last_update;
+        builder.value(
+            "rainrate",
+            rate_unit.symbol(),
+            Unit::MillimetersPerHour
+                .convert(self.rate(conf), rate_unit)
+                .unwrap(),
+            measured,
+            quality,
+        );
+        let amounts = [
+            ("rain-24h", totals.since(now - RECENT)),
+            ("rain-today", totals.today),
+            ("rain-storm", totals.storm),
+            ("rain-month", totals.month),
+            ("rain-year", totals.year),
+        ];
+        for (key, mm) in amounts {
+            let value = Unit::Millimeters.convert(mm, unit).unwrap();
+            builder.value(key, unit.symbol(), value, measured, quality);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn counter_reset() {
+        let mut totals = Totals::default();
+        assert_eq!(totals.count(5.0), 0.0);
+        assert_eq!(totals.count(7.5), 2.5);
+        assert_eq!(totals.count(7.5), 0.0);
+        // The station started counting from zero again
+        assert_eq!(totals.count(1.0), 1.0);
+        assert_eq!(totals.count(1.5), 0.5);
+    }
+
+    #[test]
+    fn totals_roll_over() {
+        let mut totals = Totals::default();
+        assert!(totals.roll(date(2023, 12, 30)));
+        totals.add(0, 1.0);
+        assert!(!totals.roll(date(2023, 12, 30)));
+        assert_eq!((totals.today, totals.month, totals.year), (1.0, 1.0, 1.0));
+
+        // A new day
+        assert!(totals.roll(date(2023, 12, 31)));
+        assert_eq!((totals.today, totals.month, totals.year), (0.0, 1.0, 1.0));
+        totals.add(0, 2.0);
+
+        // A new month and year
+        assert!(totals.roll(date(2024, 1, 1)));
+        assert_eq!((totals.today, totals.month, totals.year), (0.0, 0.0, 0.0));
+        totals.add(0, 4.0);
+
+        // A new month of the same year
+        assert!(totals.roll(date(2024, 2, 1)));
+        assert_eq!((totals.today, totals.month, totals.year), (0.0, 0.0, 4.0));
+    }
+
+    #[test]
+    fn storm_ends_after_gap() {
+        let gap = 24 * 60 * 60;
+        let mut totals = Totals::default();
+        totals.add(1000, 3.0);
+        totals.end_storm(1000 + gap, gap);
+        assert_eq!(totals.storm, 3.0);
+        totals.add(1000 + gap, 1.0);
+        totals.end_storm(1000 + 2 * gap, gap);
+        assert_eq!(totals.storm, 4.0);
+        totals.end_storm(1001 + 2 * gap, gap);
+        assert_eq!(totals.storm, 0.0);
+        // Other totals are not affected
+        assert_eq!(totals.year, 4.0);
+    }
+
+    #[test]
+    fn recent_rain() {
+        let mut totals = Totals::default();
+        totals.add(100, 1.0);
+        totals.add(200, 2.0);
+        assert_eq!(totals.since(0), 3.0);
+        assert_eq!(totals.since(100), 2.0);
+        assert_eq!(totals.since(200), 0.0);
+    }
+}
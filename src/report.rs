@@ -0,0 +1,179 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    conf::{QualityConf, SensorMapping, SensorsConf, StalePolicy},
+    mqtt::{SensorValue, Update},
+    sensor::{Quality, Sensor, Sensors},
+    units::UnitSystem,
+};
+
+/// The wall clock time `age` ago
+fn wall_clock(age: std::time::Duration) -> chrono::DateTime<chrono::Local> {
+    chrono::Local::now()
+        - chrono::Duration::from_std(age).unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+/// The station sensors behind a published key
+pub fn lookup<'s>(sensors: &'s Sensors, conf: &SensorsConf, key: &str) -> Vec<&'s Sensor> {
+    let (names, aliases) = match conf.keys.get(key) {
+        Some(mapping) => mapping.names(),
+        None => return vec![],
+    };
+    let found: Vec<_> = names.iter().filter_map(|n| sensors.get(n)).collect();
+    if !found.is_empty() {
+        return found;
+    }
+    aliases.iter().filter_map(|n| sensors.get(n)).collect()
+}
+
+/// Collects the readings of a weather update
+pub struct ReportBuilder<'a> {
+    quality: &'a QualityConf,
+    units: UnitSystem,
+    values: HashMap<String, Vec<SensorValue>>,
+    text: HashMap<String, Option<String>>,
+}
+
+impl<'a> ReportBuilder<'a> {
+    pub fn new(quality: &'a QualityConf, units: UnitSystem) -> Self {
+        Self {
+            quality,
+            units,
+            values: HashMap::new(),
+            text: HashMap::new(),
+        }
+    }
+
+    /// Add every configured key, and any unmapped sensors when passing through
+    pub fn sensors(&mut self, sensors: &Sensors, conf: &SensorsConf) -> &mut Self {
+        for (key, mapping) in &conf.keys {
+            if let SensorMapping::Enabled(_) = mapping {
+                continue;
+            }
+            let found = lookup(sensors, conf, key);
+            if found.is_empty() {
+                self.missing(key);
+            }
+            for sensor in found {
+                self.sensor(key, sensor);
+            }
+        }
+        if conf.passthrough {
+            for sensor in sensors.iter().filter(|s| !conf.is_mapped(&s.name)) {
+                self.sensor(&sensor.name, sensor);
+            }
+        }
+        self
+    }
+
+    /// Add a station sensor under `key`
+    pub fn sensor(&mut self, key: &str, sensor: &Sensor) -> &mut Self {
+        let quality = sensor.quality(self.quality);
+        let reading = self.reading(&sensor.unit, sensor.value, sensor.last_update, quality);
+        let values = self.values.entry(key.into()).or_default();
+        if let Some(mut reading) = reading {
+            values.retain(|v| v.quality != Quality::Missing);
+            if reading.value.is_some() && sensor.raw != sensor.value {
+                // In the same units as the value
+                reading.raw = Some(self.units.convert(sensor.raw, &sensor.unit).0);
+            }
+            values.push(reading);
+        }
+        self
+    }
+
+    /// Add a reading under `key`, in the station's `unit`
+    pub fn value(
+        &mut self,
+        key: &str,
+        unit: &str,
+        value: f32,
+        measured: Instant,
+        quality: Quality,
+    ) -> &mut Self {
+        let reading = self.reading(unit, value, measured, quality);
+        let values = self.values.entry(key.into()).or_default();
+        if reading.is_some() {
+            // A reading replaces the note that the key is missing
+            values.retain(|v| v.quality != Quality::Missing);
+        }
+        values.extend(reading);
+        self
+    }
+
+    /// Whether nothing has been found for `key` yet
+    pub fn is_missing(&self, key: &str) -> bool {
+        match self.values.get(key) {
+            Some(values) => values.iter().all(|v| v.quality == Quality::Missing),
+            None => true,
+        }
+    }
+
+    /// A reading as the quality policy wants it published
+    fn reading(
+        &self,
+        unit: &str,
+        value: f32,
+        measured: Instant,
+        quality: Quality,
+    ) -> Option<SensorValue> {
+        let (value, unit) = self.units.convert(value, unit);
+        let value = match (quality, self.quality.stale) {
+            (Quality::Fresh, _) | (_, StalePolicy::Keep) => Some(value),
+            (_, StalePolicy::Null) => None,
+            (_, StalePolicy::Omit) => return None,
+        };
+        let age = measured.elapsed();
+        Some(SensorValue {
+            unit: Some(unit.into()),
+            value,
+            raw: None,
+            quality,
+            time: Some(wall_clock(age).to_rfc3339()),
+            age: Some(age.as_millis() as u64),
+        })
+    }
+
+    /// Note that nothing could be found for `key`
+    ///
+    /// The key is published empty unless a missing marker is configured.
+    pub fn missing(&mut self, key: &str) -> &mut Self {
+        let values = self.values.entry(key.into()).or_default();
+        if self.quality.missing {
+            values.push(SensorValue {
+                unit: None,
+                value: None,
+                raw: None,
+                quality: Quality::Missing,
+                time: None,
+                age: None,
+            });
+        }
+        self
+    }
+
+    /// Add a reading that isn't a number under `key`
+    pub fn text(&mut self, key: &str, text: impl Into<String>) -> &mut Self {
+        self.text.insert(key.into(), Some(text.into()));
+        self
+    }
+
+    /// Note that nothing could be found for the text reading `key`
+    ///
+    /// It is left out unless a missing marker is configured.
+    pub fn missing_text(&mut self, key: &str) -> &mut Self {
+        if self.quality.missing {
+            self.text.entry(key.into()).or_insert(None);
+        }
+        self
+    }
+
+    pub fn build(self, id: String) -> Update {
+        Update {
+            time: chrono::Local::now().to_rfc3339(),
+            id,
+            sensors: self.values,
+            text: self.text,
+        }
+    }
+}
@@ -1,25 +1,61 @@
-use ordoo::or_do;
 use scode_rs::CodeSend;
+use serde::Serialize;
 use std::{
-    collections::{btree_map, BTreeMap},
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap},
     fmt::Display,
     iter::Map,
-    sync::{Arc, Mutex},
-    time::Instant,
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use crate::station::Rule;
+use crate::{
+    conf::{Calibration, QualityConf},
+    history::{Histories, History, Stats},
+    protocol::{SensorMask, SensorReport, StationMessage},
+    station::Rule,
+};
 
 #[derive(Debug)]
 pub struct Sensor {
     pub name: Arc<str>,
     pub unit: Arc<str>,
     pub id: u8,
+    /// The calibrated value
     pub value: f32,
+    /// The value as the station reported it
+    pub raw: f32,
     pub last_update: Instant,
     pub auto: bool,
 }
 
+/// How much a reading can be trusted, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Quality {
+    Fresh,
+    /// The sensor hasn't reported within its max age
+    Stale,
+    /// The reading is outside of the sensor's configured limits
+    OutOfRange,
+    /// The sensor doesn't exist
+    Missing,
+}
+
+impl Sensor {
+    /// Judge the current reading against the quality configuration
+    pub fn quality(&self, conf: &QualityConf) -> Quality {
+        let limits = conf.sensors.get(&*self.name);
+        let max_age = limits.and_then(|l| l.max_age).unwrap_or(conf.max_age);
+        if self.last_update.elapsed() > Duration::from_secs_f32(max_age) {
+            return Quality::Stale;
+        }
+        if !conf.in_range(&self.name, self.value) {
+            return Quality::OutOfRange;
+        }
+        Quality::Fresh
+    }
+}
+
 impl Display for Sensor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}: {} {}", self.name, self.value, self.unit))?;
@@ -30,10 +66,101 @@ impl Display for Sensor {
     }
 }
 
+/// A change to the set of sensors
+#[derive(Debug, Clone)]
+pub enum SensorEvent {
+    /// A sensor reported without ever telling us its name and unit
+    Unknown(u8),
+    Added {
+        id: u8,
+        name: Arc<str>,
+        unit: Arc<str>,
+    },
+    Changed {
+        id: u8,
+        name: Arc<str>,
+        unit: Arc<str>,
+        old_name: Arc<str>,
+        old_unit: Arc<str>,
+    },
+    Removed {
+        id: u8,
+        name: Arc<str>,
+        unit: Arc<str>,
+    },
+}
+
+/// A problem found while re-syncing the sensors
+#[derive(Debug, Clone)]
+pub enum SyncConflict {
+    /// More than one sensor has the same name, only `kept` can be found by name
+    DuplicateName {
+        name: Arc<str>,
+        kept: u8,
+        dropped: u8,
+    },
+    /// A sensor was reported with only one of its name and unit, and was left as it was
+    Incomplete(u8),
+}
+
+impl Display for SyncConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateName {
+                name,
+                kept,
+                dropped,
+            } => f.write_fmt(format_args!(
+                "sensors {kept} and {dropped} are both named {name:?}, using {kept}"
+            )),
+            Self::Incomplete(id) => f.write_fmt(format_args!(
+                "sensor {id} was reported without both a name and a unit"
+            )),
+        }
+    }
+}
+
+/// Everything that changed during a re-sync
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub events: Vec<SensorEvent>,
+    pub conflicts: Vec<SyncConflict>,
+}
+
 #[derive(Debug)]
 pub struct Sensors {
     sensors: BTreeMap<u8, Sensor>,
     map: BTreeMap<Arc<str>, u8>,
+    autos: SensorMask,
+    /// Sensors that reported before we knew about them
+    unknown: BTreeSet<u8>,
+    calibration: HashMap<String, Calibration>,
+    history: Histories,
+}
+
+/// Correct a raw value with the sensor's calibration, if it has one
+fn calibrate(calibration: &HashMap<String, Calibration>, name: &str, raw: f32) -> f32 {
+    match calibration.get(name) {
+        Some(c) => c.apply(raw),
+        None => raw,
+    }
+}
+
+/// Find the sensor by `name`, unless another sensor already has the name
+fn claim_name(map: &mut BTreeMap<Arc<str>, u8>, name: &Arc<str>, id: u8) {
+    match map.get(name) {
+        Some(kept) if *kept != id => {
+            let conflict = SyncConflict::DuplicateName {
+                name: name.clone(),
+                kept: *kept,
+                dropped: id,
+            };
+            eprintln!("sensor conflict: {conflict}");
+        }
+        _ => {
+            map.insert(name.clone(), id);
+        }
+    }
 }
 
 impl Display for Sensors {
@@ -49,52 +176,203 @@ impl Display for Sensors {
 }
 
 impl Sensors {
-    pub fn new() -> Self {
+    pub fn new(calibration: HashMap<String, Calibration>, history: Histories) -> Self {
         Self {
             sensors: BTreeMap::new(),
             map: BTreeMap::new(),
+            autos: SensorMask::default(),
+            unknown: BTreeSet::new(),
+            calibration,
+            history,
         }
     }
 
-    pub fn put(&mut self, code: &CodeSend) -> bool {
-        if code.letter != b'S' {
-            return false;
-        }
+    /// Store a sensor report
+    ///
+    /// Returns an event when the set of sensors changed, or when the report
+    /// came from a sensor that still has to be asked for its name and unit.
+    pub fn put(&mut self, report: SensorReport) -> Option<SensorEvent> {
         let now = Instant::now();
-        let value = or_do!(code.find(b'V'), return false);
-        let value = or_do!(
-            value.value.as_borrowed().cast_f32(),
-            v => or_do!(
-                std::str::from_utf8(v).ok().map(|v| v.parse().ok()).flatten(),
-                return false
-            )
-        );
-
-        if let Some(sensor) = self.sensors.get_mut(&code.number) {
+
+        if let Some(sensor) = self.sensors.get_mut(&report.id) {
+            let name = report.name.filter(|n| n.as_str() != &*sensor.name);
+            let unit = report.unit.filter(|u| u.as_str() != &*sensor.unit);
+
             sensor.last_update = now;
-            sensor.value = value;
+            sensor.raw = report.value;
+            // A renamed sensor is calibrated by its new name
+            let calibrated_name = name.as_deref().unwrap_or(&sensor.name);
+            sensor.value = calibrate(&self.calibration, calibrated_name, report.value);
+            self.history.push(calibrated_name, now, sensor.value);
+            if name.is_none() && unit.is_none() {
+                return None;
+            }
+            let old_name = sensor.name.clone();
+            let old_unit = sensor.unit.clone();
+            if let Some(unit) = unit {
+                sensor.unit = unit.into();
+            }
+            if let Some(name) = name {
+                sensor.name = name.into();
+                if self.map.get(&old_name) == Some(&sensor.id) {
+                    self.map.remove(&old_name);
+                }
+                claim_name(&mut self.map, &sensor.name, sensor.id);
+            }
+            Some(SensorEvent::Changed {
+                id: sensor.id,
+                name: sensor.name.clone(),
+                unit: sensor.unit.clone(),
+                old_name,
+                old_unit,
+            })
         } else {
-            let name = or_do!(code.find(b'N'), return false)
-                .value
-                .as_borrowed()
-                .cast_bytes();
-            let unit = or_do!(code.find(b'U'), return false)
-                .value
-                .as_borrowed()
-                .cast_bytes();
+            let (name, unit) = match (report.name, report.unit) {
+                (Some(name), Some(unit)) => (name, unit),
+                _ => {
+                    // Only ask about an unknown sensor once
+                    return self
+                        .unknown
+                        .insert(report.id)
+                        .then_some(SensorEvent::Unknown(report.id));
+                }
+            };
+            self.unknown.remove(&report.id);
             let sensor = Sensor {
-                name: String::from_utf8_lossy(&name).into(),
-                unit: String::from_utf8_lossy(&unit).into(),
-                id: code.number,
-                value,
+                value: calibrate(&self.calibration, &name, report.value),
+                raw: report.value,
+                name: name.into(),
+                unit: unit.into(),
+                id: report.id,
                 last_update: now,
-                auto: false,
+                // The autos may have arrived before the sensor did
+                auto: self.autos.contains(report.id),
+            };
+            let event = SensorEvent::Added {
+                id: sensor.id,
+                name: sensor.name.clone(),
+                unit: sensor.unit.clone(),
             };
+            self.history.push(&sensor.name, now, sensor.value);
 
-            self.map.insert(sensor.name.clone(), sensor.id);
+            claim_name(&mut self.map, &sensor.name, sensor.id);
             self.sensors.insert(sensor.id, sensor);
+            Some(event)
+        }
+    }
+
+    /// Stop waiting on the name and unit of an unknown sensor
+    ///
+    /// Its next value only report will be announced as unknown again.
+    pub fn forget_unknown(&mut self, id: u8) {
+        self.unknown.remove(&id);
+    }
+
+    /// Replace every sensor with the station's full list of sensors
+    ///
+    /// Both maps are rebuilt from scratch, so stale names and ids can't
+    /// survive a rename or renumbering. When several sensors share a name,
+    /// the first one reported keeps it.
+    ///
+    /// Value only reports aren't part of the listing and are skipped.
+    /// Sensors are only removed when every report had a name and a unit,
+    /// otherwise the ones that weren't listed are kept as they were.
+    ///
+    /// The reports are not added to the history, since the `M1` replies
+    /// have already been through `put`.
+    pub fn resync(&mut self, reports: impl IntoIterator<Item = SensorReport>) -> SyncReport {
+        let now = Instant::now();
+        let mut report = SyncReport::default();
+        let mut sensors = BTreeMap::new();
+        let mut map = BTreeMap::new();
+        let mut complete = true;
+
+        for sensor in reports {
+            let (name, unit): (Arc<str>, Arc<str>) = match (sensor.name, sensor.unit) {
+                (Some(name), Some(unit)) => (name.into(), unit.into()),
+                // An auto report that arrived in the middle of the listing
+                (None, None) => continue,
+                _ => {
+                    report.conflicts.push(SyncConflict::Incomplete(sensor.id));
+                    complete = false;
+                    continue;
+                }
+            };
+            if let Some(kept) = map.get(&name) {
+                if *kept != sensor.id {
+                    report.conflicts.push(SyncConflict::DuplicateName {
+                        name: name.clone(),
+                        kept: *kept,
+                        dropped: sensor.id,
+                    });
+                }
+            } else {
+                map.insert(name.clone(), sensor.id);
+            }
+
+            let event = match self.sensors.get(&sensor.id) {
+                None => Some(SensorEvent::Added {
+                    id: sensor.id,
+                    name: name.clone(),
+                    unit: unit.clone(),
+                }),
+                Some(old) if old.name != name || old.unit != unit => Some(SensorEvent::Changed {
+                    id: sensor.id,
+                    name: name.clone(),
+                    unit: unit.clone(),
+                    old_name: old.name.clone(),
+                    old_unit: old.unit.clone(),
+                }),
+                Some(_) => None,
+            };
+            report.events.extend(event);
+
+            sensors.insert(
+                sensor.id,
+                Sensor {
+                    unit,
+                    id: sensor.id,
+                    value: calibrate(&self.calibration, &name, sensor.value),
+                    raw: sensor.value,
+                    name,
+                    last_update: now,
+                    auto: self.autos.contains(sensor.id),
+                },
+            );
+        }
+
+        // Without any full report there is nothing to go by
+        let complete = complete && !sensors.is_empty();
+        for (id, old) in std::mem::take(&mut self.sensors) {
+            if sensors.contains_key(&id) {
+                continue;
+            }
+            if complete {
+                report.events.push(SensorEvent::Removed {
+                    id,
+                    name: old.name,
+                    unit: old.unit,
+                });
+            } else {
+                map.entry(old.name.clone()).or_insert(id);
+                sensors.insert(id, old);
+            }
         }
-        true
+
+        self.unknown.retain(|id| !sensors.contains_key(id));
+        self.sensors = sensors;
+        self.map = map;
+        report
+    }
+
+    /// The recent values of a sensor
+    pub fn history(&self, name: impl AsRef<str>) -> Option<&History> {
+        self.history.get(name.as_ref())
+    }
+
+    /// Statistics of a sensor over the last `window`
+    pub fn stats(&self, name: impl AsRef<str>, window: Duration) -> Option<Stats> {
+        self.history(name)?.stats(window)
     }
 
     pub fn get(&self, name: impl AsRef<str>) -> Option<&Sensor> {
@@ -114,39 +392,53 @@ impl Sensors {
         self.into_iter()
     }
 
+    /// Set which sensors are reported automatically
+    pub fn set_autos(&mut self, autos: SensorMask) {
+        self.autos = autos;
+        for sensor in self.iter_mut() {
+            sensor.auto = autos.contains(sensor.id);
+        }
+    }
+
     pub fn autos_callback(sensors: &Arc<Mutex<Sensors>>) -> (Rule, impl Fn(&CodeSend) -> bool) {
         let sensors = sensors.clone();
         (Rule::new(b'M', 102), move |code| {
-            let mut val: i64 = 0;
-            for p in &code.params {
-                match p.letter {
-                    b'E' => {
-                        let v = p.value.as_borrowed().cast_u8().unwrap();
-                        val |= 1 << v
-                    }
-                    b'D' => {
-                        let v = p.value.as_borrowed().cast_u8().unwrap();
-                        val &= !(1 << v)
-                    }
-                    b'V' => {
-                        let v = p.value.as_borrowed().cast_i64().unwrap();
-                        val = v
-                    }
-                    _ => unreachable!(),
+            let update = match StationMessage::parse(code) {
+                Ok(StationMessage::AutosMask(update)) => update,
+                Ok(_) => return false,
+                Err(err) => {
+                    eprintln!("{err}");
+                    return true;
                 }
+            };
+            for err in &update.diagnostics {
+                eprintln!("ignoring part of the autos: {err}");
             }
-            let mut s = sensors.lock().unwrap();
-            for sensor in s.iter_mut() {
-                sensor.auto = (val & (1 << sensor.id)) != 0;
-            }
+            sensors.lock().unwrap().set_autos(update.mask());
             true
         })
     }
 
-    pub fn sensor_callback(sensor: &Arc<Mutex<Self>>) -> (Rule, impl Fn(&CodeSend) -> bool) {
+    /// Store sensor reports, sending any resulting events to `events`
+    pub fn sensor_callback<T>(
+        sensor: &Arc<Mutex<Self>>,
+        events: Sender<T>,
+    ) -> (Rule, impl Fn(&CodeSend) -> bool)
+    where
+        T: From<SensorEvent> + Send,
+    {
         let sensor = sensor.clone();
         (Rule::letter(b'S'), move |code| {
-            sensor.lock().unwrap().put(code);
+            match StationMessage::parse(code) {
+                Ok(StationMessage::SensorReport(report)) => {
+                    let event = sensor.lock().unwrap().put(report);
+                    if let Some(event) = event {
+                        events.send(T::from(event)).unwrap();
+                    }
+                }
+                Ok(_) => return false,
+                Err(err) => eprintln!("{err}"),
+            }
             true
         })
     }
@@ -182,3 +474,90 @@ impl<'a> IntoIterator for &'a mut Sensors {
         self.sensors.iter_mut().map(|(_, v)| v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensors() -> Sensors {
+        Sensors::new(HashMap::new(), Histories::new(Duration::from_secs(60), 16))
+    }
+
+    fn full(id: u8, name: &str, unit: &str) -> SensorReport {
+        SensorReport {
+            id,
+            name: Some(name.into()),
+            unit: Some(unit.into()),
+            value: 1.0,
+        }
+    }
+
+    fn value(id: u8) -> SensorReport {
+        SensorReport {
+            id,
+            name: None,
+            unit: None,
+            value: 1.0,
+        }
+    }
+
+    fn listed(sensors: &Sensors) -> Vec<u8> {
+        sensors.iter().map(|s| s.id).collect()
+    }
+
+    #[test]
+    fn complete_listing_removes_sensors() {
+        let mut sensors = sensors();
+        sensors.resync([full(0, "temp", "C"), full(1, "humidity", "%")]);
+
+        let report = sensors.resync([full(0, "temp", "C"), value(1)]);
+        assert!(report.conflicts.is_empty());
+        assert!(matches!(
+            report.events[..],
+            [SensorEvent::Removed { id: 1, .. }]
+        ));
+        assert_eq!(listed(&sensors), vec![0]);
+        assert!(sensors.get("humidity").is_none());
+    }
+
+    #[test]
+    fn partial_listing_keeps_sensors() {
+        let mut sensors = sensors();
+        sensors.resync([
+            full(0, "temp", "C"),
+            full(1, "humidity", "%"),
+            full(2, "uv", "index"),
+        ]);
+
+        let partial = SensorReport {
+            unit: None,
+            ..full(1, "humidity", "%")
+        };
+        let report = sensors.resync([full(0, "temp", "C"), partial]);
+        assert!(matches!(
+            report.conflicts[..],
+            [SyncConflict::Incomplete(1)]
+        ));
+        assert!(report.events.is_empty());
+        assert_eq!(listed(&sensors), vec![0, 1, 2]);
+        assert_eq!(sensors.get("humidity").unwrap().id, 1);
+        assert_eq!(sensors.get("uv").unwrap().id, 2);
+
+        // Without a single full report nothing can be told apart
+        let report = sensors.resync([value(0)]);
+        assert!(report.events.is_empty());
+        assert_eq!(listed(&sensors), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rename_keeps_the_name_of_another_sensor() {
+        let mut sensors = sensors();
+        sensors.put(full(0, "temp", "C"));
+        sensors.put(full(1, "humidity", "%"));
+
+        let event = sensors.put(full(1, "temp", "%"));
+        assert!(matches!(event, Some(SensorEvent::Changed { id: 1, .. })));
+        assert_eq!(sensors.get("temp").unwrap().id, 0);
+        assert!(sensors.get("humidity").is_none());
+    }
+}
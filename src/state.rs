@@ -0,0 +1,33 @@
+use color_eyre::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fs, io::ErrorKind, path::Path};
+
+/// Read state kept by `save`, or start over if there is none
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> T {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return T::default(),
+        Err(err) => {
+            eprintln!("could not read {path:?}, starting over: {err}");
+            return T::default();
+        }
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        eprintln!("could not parse {path:?}, starting over: {err}");
+        T::default()
+    })
+}
+
+/// Keep state across restarts
+///
+/// The state is written next to `path` and then moved over it, so a crash
+/// can't leave it half written.
+pub fn save<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -1,16 +1,21 @@
-use chrono::{NaiveDate, NaiveTime};
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
 use ordoo::or_do;
+use rand::Rng;
 use std::{
+    future::Future,
+    pin::Pin,
     sync::{
-        mpsc::{Receiver, Sender},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
         Arc, Mutex,
     },
+    task::{Context, Poll, Waker},
+    thread,
     time::{Duration, Instant},
 };
 
-use rppal::uart::Uart;
-use scode_rs::{error::ScodeError, Code, CodeSend, CodeStream, ParamSend, ParamValue};
+use scode_rs::{error::ScodeError, Code, CodeSend, CodeStream};
+
+use crate::{protocol::StationMessage, transport::Transport};
 
 #[derive(Debug, Default)]
 pub struct Rule {
@@ -54,8 +59,20 @@ impl Rule {
     }
 }
 
+/// The state of the connection to the station
+#[derive(Debug, Clone)]
+pub enum LinkState {
+    Up,
+    Down(String),
+}
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+type Connect = Box<dyn FnMut() -> Result<Box<dyn Transport>> + Send>;
+
 pub struct StationReader<T> {
-    uart: Uart,
+    connect: Connect,
     on_recv: Sender<T>,
     on_send: Receiver<CodeSend>,
     last_send: Instant,
@@ -65,11 +82,18 @@ pub struct StationReader<T> {
 
 impl<T> StationReader<T>
 where
-    T: From<CodeSend> + From<ScodeError>,
+    T: From<CodeSend> + From<ScodeError> + From<LinkState>,
 {
-    pub fn new(uart: Uart, on_recv: Sender<T>, on_send: Receiver<CodeSend>) -> Self {
+    /// Create a reader that opens its transport with `connect`
+    ///
+    /// `connect` is called again whenever the link to the station is lost.
+    pub fn new(
+        connect: impl FnMut() -> Result<Box<dyn Transport>> + Send + 'static,
+        on_recv: Sender<T>,
+        on_send: Receiver<CodeSend>,
+    ) -> Self {
         Self {
-            uart,
+            connect: Box::new(connect),
             on_recv,
             on_send,
             last_send: Instant::now(),
@@ -78,20 +102,77 @@ where
         }
     }
 
+    /// Keep the station connected, reopening the transport whenever it fails
+    ///
+    /// Link changes are reported through `on_recv`. Reconnection attempts
+    /// back off exponentially up to a minute apart.
+    pub fn run(&mut self) {
+        let mut backoff = BACKOFF_MIN;
+        let mut up = None;
+        loop {
+            let err = match (self.connect)() {
+                Ok(mut transport) => {
+                    backoff = BACKOFF_MIN;
+                    up = Some(true);
+                    self.on_recv.send(T::from(LinkState::Up)).unwrap();
+                    self.to_send.clear();
+                    self.bytes_sent = 0;
+                    match self.main(transport.as_mut()) {
+                        Ok(()) => eyre!("the station link closed"),
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => err,
+            };
+            eprintln!("station link down: {err:#}");
+            if up != Some(false) {
+                up = Some(false);
+                self.on_recv
+                    .send(T::from(LinkState::Down(format!("{err:#}"))))
+                    .unwrap();
+            }
+            self.discard_for(backoff);
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+        }
+    }
+
+    /// Wait while dropping anything that would be sent to the station
+    ///
+    /// Guaranteed commands are resent by the `CommandManager` once the link
+    /// is back, so there is no point in queueing them up meanwhile.
+    fn discard_for(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            if let Err(RecvTimeoutError::Disconnected) = self.on_send.recv_timeout(until - now) {
+                thread::sleep(until - now);
+            }
+        }
+    }
+
     /// The main loop for communicating with the station sensors
     ///
-    /// This will poll the station every 150ms
-    pub fn main(&mut self) -> Result<()> {
-        self.uart.set_read_mode(0, Duration::ZERO)?;
+    /// This will poll the station every 150ms until the transport fails
+    pub fn main(&mut self, transport: &mut dyn Transport) -> Result<()> {
+        transport.set_timeout(Duration::ZERO)?;
         let mut stream = CodeStream::with_capacity(64);
 
         let mut buf = [0; 64];
         loop {
-            let len = self.uart.read(&mut buf)?;
+            let len = transport.read(&mut buf)?;
             if len == 0 {
+                if !transport.is_connected() {
+                    return Err(eyre!("the station was disconnected"));
+                }
                 match self.on_send.recv_timeout(Duration::from_millis(150)) {
                     Ok(to_send) => {
-                        let code = Code::try_from(to_send)?;
+                        let code = or_do!(Code::try_from(to_send), err => {
+                            eprintln!("could not encode code: {err}");
+                            continue
+                        });
                         let mut buf = code.dump_binary_vec()?;
                         self.to_send.append(&mut buf);
                     }
@@ -109,7 +190,7 @@ where
                     }
                     let len = self.to_send.len().min(allotment as usize);
                     let to_send = &self.to_send[0..len];
-                    self.uart.write(to_send)?;
+                    let len = transport.write(to_send)?;
                     self.to_send.drain(0..len);
                     self.bytes_sent += len as isize;
                 }
@@ -160,45 +241,338 @@ impl CodeHandler {
     }
 }
 
+/// How persistently a command is resent until the station acknowledges it
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Delay before the first resend
+    pub retry: Duration,
+    /// Factor the delay grows by after every resend
+    pub backoff: f32,
+    /// Upper bound for the delay between resends
+    pub max_retry: Duration,
+    /// Random spread of each delay as a fraction of the delay
+    pub jitter: f32,
+    /// Give up after the command has been sent this many times
+    pub max_attempts: Option<u32>,
+    /// Give up once this much time has passed since the first send
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(retry: Duration) -> Self {
+        Self {
+            retry,
+            backoff: 2.0,
+            max_retry: Duration::from_secs(10),
+            jitter: 0.1,
+            max_attempts: Some(5),
+            deadline: None,
+        }
+    }
+
+    /// Keep resending until the station answers
+    ///
+    /// A `deadline` set afterwards still applies.
+    pub fn forever(mut self) -> Self {
+        self.max_attempts = None;
+        self.deadline = None;
+        self
+    }
+
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    fn delay(&self, delay: Duration) -> Duration {
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let spread = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        delay.mul_f32((1.0 + spread).max(0.0))
+    }
+}
+
+impl From<Duration> for RetryPolicy {
+    fn from(value: Duration) -> Self {
+        Self::new(value)
+    }
+}
+
+/// The outcome of a guaranteed command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    /// The station acknowledged the command with `O1`
+    Acknowledged,
+    /// The station never answered within the retry policy
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandResult {
+    pub key: (u8, u8),
+    pub status: CommandStatus,
+    /// Codes the station sent in reply before answering the command
+    pub replies: Vec<CodeSend>,
+}
+
+impl CommandResult {
+    /// Turn anything but an acknowledgement into an error
+    pub fn into_response(self) -> Result<Response> {
+        let (letter, number) = self.key;
+        match self.status {
+            CommandStatus::Acknowledged => Ok(Response {
+                key: self.key,
+                replies: self.replies,
+            }),
+            CommandStatus::TimedOut => Err(eyre!(
+                "{}{number} was not answered by the station",
+                letter as char
+            )),
+        }
+    }
+}
+
+/// The station's answer to an acknowledged command
+#[derive(Debug, Clone)]
+pub struct Response {
+    #[allow(dead_code)]
+    pub key: (u8, u8),
+    pub replies: Vec<CodeSend>,
+}
+
+type SharedWaker = Arc<Mutex<Option<Waker>>>;
+
+/// A command that is waiting for the station to answer
+///
+/// Either block on it with `wait`, or `.await` it.
+pub struct PendingResponse {
+    rx: Receiver<CommandResult>,
+    waker: SharedWaker,
+}
+
+impl PendingResponse {
+    /// Block until the station answers or the retry policy gives up
+    pub fn wait(self) -> Result<Response> {
+        match self.rx.recv() {
+            Ok(result) => result.into_response(),
+            Err(_) => Err(eyre!("the command was dropped")),
+        }
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Result<Response>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register before checking so an answer in between still wakes us
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.rx.try_recv() {
+            Ok(result) => Poll::Ready(result.into_response()),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(eyre!("the command was dropped"))),
+        }
+    }
+}
+
+/// One transmission of a command, in the order they were sent
+struct Sent {
+    key: (u8, u8),
+    /// Whether the command asks for names and units along with the values
+    full: bool,
+    /// The waiting command, or `None` once it stopped waiting on this send
+    owner: Option<u64>,
+    /// When the send was due to be repeated
+    ///
+    /// A send nobody waits on anymore can still be answered until then, and
+    /// its late answer is swallowed instead of resolving a newer command.
+    /// After that its answer is taken to be lost.
+    until: Instant,
+    replies: Vec<CodeSend>,
+}
+
+impl Sent {
+    fn new(code: &CodeSend, owner: u64, until: Instant) -> Self {
+        Self {
+            key: (code.letter, code.number),
+            full: code.find(b'R').is_none(),
+            owner: Some(owner),
+            until,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Whether `code` can be a reply to this send
+    ///
+    /// `M1` is answered with a full report of every sensor and `S<n>` with a
+    /// single report of that sensor, which is only the value when `R` was
+    /// given. Everything else is answered with one code of the same letter
+    /// and number. Auto reports are value only, so they are never taken as
+    /// part of an `M1`.
+    fn accepts(&self, code: &CodeSend) -> bool {
+        let full = code.find(b'N').is_some();
+        let same = (code.letter, code.number) == self.key;
+        match self.key {
+            (b'M', 1) => code.letter == b'S' && full,
+            (b'S', _) => same && full == self.full && self.replies.is_empty(),
+            _ => same && self.replies.is_empty(),
+        }
+    }
+}
+
 struct Waiting {
+    id: u64,
     key: (u8, u8),
     code: CodeSend,
+    policy: RetryPolicy,
+    sent: Instant,
+    attempts: u32,
+    delay: Duration,
     due: Instant,
-    retry: Duration,
-    notify: Sender<(u8, u8)>,
+    notify: Sender<CommandResult>,
+    waker: Option<SharedWaker>,
+}
+
+impl Waiting {
+    fn resolve(self, status: CommandStatus, replies: Vec<CodeSend>) {
+        // The caller may have stopped waiting, which is fine
+        let _ = self.notify.send(CommandResult {
+            key: self.key,
+            status,
+            replies,
+        });
+        if let Some(shared) = self.waker {
+            if let Some(waker) = shared.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        if let Some(max) = self.policy.max_attempts {
+            if self.attempts >= max {
+                return true;
+            }
+        }
+        if let Some(deadline) = self.policy.deadline {
+            if now >= self.sent + deadline {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Schedule the next resend, never later than the deadline
+    fn schedule(&mut self, now: Instant) {
+        self.due = now + self.policy.delay(self.delay);
+        if let Some(deadline) = self.policy.deadline {
+            self.due = self.due.min(self.sent + deadline);
+        }
+        self.delay = self
+            .delay
+            .mul_f32(self.policy.backoff)
+            .min(self.policy.max_retry);
+    }
+}
+
+#[derive(Default)]
+struct Commands {
+    /// Waiting commands in the order they were first sent
+    waiting: Vec<Waiting>,
+    /// Every send that may still be answered, oldest first
+    sent: Vec<Sent>,
+    next_id: u64,
+}
+
+impl Commands {
+    /// Stop waiting on the sends of a command that is no longer waiting
+    fn orphan(&mut self, id: u64) {
+        for s in self.sent.iter_mut().filter(|s| s.owner == Some(id)) {
+            s.owner = None;
+        }
+    }
+
+    /// Forget the sends nobody waits on that can't be answered anymore
+    fn prune(&mut self, now: Instant) {
+        self.sent.retain(|s| s.owner.is_some() || now < s.until);
+    }
+
+    /// Hand a reply to the oldest send it can belong to
+    fn reply(&mut self, code: &CodeSend) {
+        self.prune(Instant::now());
+        if let Some(s) = self.sent.iter_mut().find(|s| s.accepts(code)) {
+            s.replies.push(code.clone());
+        }
+    }
+
+    /// Resolve the command behind the oldest send with this key
+    fn answer(&mut self, key: (u8, u8)) {
+        self.prune(Instant::now());
+        let i = or_do!(self.sent.iter().position(|s| s.key == key), return);
+        let sent = self.sent.remove(i);
+        // A late answer to a send nobody waits on anymore
+        let id = or_do!(sent.owner, return);
+        let i = or_do!(self.waiting.iter().position(|w| w.id == id), return);
+        let w = self.waiting.remove(i);
+        self.orphan(id);
+        w.resolve(CommandStatus::Acknowledged, sent.replies);
+    }
 }
 
+/// Sends commands to the station and matches them with its answers
+///
+/// The station handles codes in the order they arrive, so every send is
+/// answered in turn: an `O1` belongs to the oldest unanswered send with that
+/// letter and number, and resolves the command it was sent for with the
+/// replies that send collected. Each reply goes to the oldest send that can
+/// take it. Once a command is resolved or gives up, its other sends are kept
+/// until they would have been resent, so that late answers to them are
+/// swallowed rather than resolving a newer command with the same key.
 pub struct CommandManager {
-    waiting: Mutex<Vec<Waiting>>,
+    commands: Mutex<Commands>,
     tx: Mutex<Sender<CodeSend>>,
 }
 
 impl CommandManager {
     pub fn new(tx: Sender<CodeSend>) -> Self {
         Self {
-            waiting: Mutex::new(Vec::new()),
+            commands: Mutex::new(Commands::default()),
             tx: Mutex::new(tx),
         }
     }
 
     pub fn on_command(self: &Arc<Self>) -> (Rule, impl Fn(&CodeSend) -> bool) {
         let s = self.clone();
-        (Rule::new(b'O', 1), move |code| {
-            let mut waiting = s.waiting.lock().unwrap();
-            if let Some(c) = code.params.first() {
-                let number = or_do!(c.value.as_borrowed().cast_u8(), _ => return false);
-                if let Some((i, _)) = waiting
-                    .iter()
-                    .enumerate()
-                    .find(|(_, v)| v.key == (c.letter, number))
-                {
-                    let v = waiting.swap_remove(i);
-                    v.notify.send((c.letter, number)).unwrap();
+        (Rule::letter(b'O'), move |code| {
+            let key = match StationMessage::parse(code) {
+                Ok(StationMessage::Ack { letter, number }) => (letter, number),
+                Ok(_) => return false,
+                Err(err) => {
+                    eprintln!("{err}");
+                    return false;
                 }
-                true
-            } else {
-                false
+            };
+            s.commands.lock().unwrap().answer(key);
+            true
+        })
+    }
+
+    /// Collect replies for waiting commands
+    ///
+    /// This never consumes the code, so it should be registered before any
+    /// other callback that handles the same codes.
+    pub fn on_reply(self: &Arc<Self>) -> (Rule, impl Fn(&CodeSend) -> bool) {
+        let s = self.clone();
+        (Rule::default(), move |code| {
+            if code.letter != b'O' {
+                s.commands.lock().unwrap().reply(code);
             }
+            false
         })
     }
 
@@ -206,92 +580,437 @@ impl CommandManager {
         self.tx.lock().unwrap().send(code).unwrap();
     }
 
-    pub fn command_guarentee(&self, code: CodeSend, tx: Sender<(u8, u8)>, retry: Duration) {
-        let mut waiting = self.waiting.lock().unwrap();
+    /// Send a command and keep resending it until the station answers
+    ///
+    /// The outcome is sent to `tx` once the station acknowledges the
+    /// command, or once the retry policy gives up.
+    #[allow(dead_code)]
+    pub fn command_guarentee(
+        &self,
+        code: CodeSend,
+        tx: Sender<CommandResult>,
+        retry: impl Into<RetryPolicy>,
+    ) {
+        self.push(code, tx, retry.into(), None);
+    }
+
+    /// Send a command and block until the station answers it
+    ///
+    /// Gives up with an error if there is no answer within `timeout`.
+    pub fn request(&self, code: CodeSend, timeout: Duration) -> Result<Response> {
+        self.request_async(
+            code,
+            RetryPolicy::new(Duration::from_secs(1)).deadline(timeout),
+        )
+        .wait()
+    }
+
+    /// Send a command without waiting for the answer
+    pub fn request_async(&self, code: CodeSend, retry: impl Into<RetryPolicy>) -> PendingResponse {
+        let (tx, rx) = mpsc::channel();
+        let waker = SharedWaker::default();
+        self.push(code, tx, retry.into(), Some(waker.clone()));
+        PendingResponse { rx, waker }
+    }
+
+    fn push(
+        &self,
+        code: CodeSend,
+        notify: Sender<CommandResult>,
+        policy: RetryPolicy,
+        waker: Option<SharedWaker>,
+    ) {
+        let mut commands = self.commands.lock().unwrap();
         self.tx.lock().unwrap().send(code.clone()).unwrap();
-        waiting.push(Waiting {
+        let now = Instant::now();
+        let id = commands.next_id;
+        commands.next_id += 1;
+        let mut w = Waiting {
+            id,
             key: (code.letter, code.number),
             code,
-            due: Instant::now() + retry,
-            retry,
-            notify: tx,
-        });
+            policy,
+            sent: now,
+            attempts: 1,
+            delay: policy.retry,
+            due: now,
+            notify,
+            waker,
+        };
+        w.schedule(now);
+        commands.sent.push(Sent::new(&w.code, id, w.due));
+        commands.waiting.push(w);
     }
 
+    /// Resend any commands that are due, and give up on expired ones
     pub fn update(&self) {
-        let mut waiting = self.waiting.lock().unwrap();
+        let mut commands = self.commands.lock().unwrap();
         let now = Instant::now();
+        commands.prune(now);
 
-        for waiting in waiting.iter_mut().filter(|w| w.due <= now) {
-            waiting.due = now + waiting.retry;
-            self.tx.lock().unwrap().send(waiting.code.clone()).unwrap();
+        let mut i = 0;
+        while i < commands.waiting.len() {
+            let w = &mut commands.waiting[i];
+            if w.due > now {
+                i += 1;
+                continue;
+            }
+            if w.expired(now) {
+                let w = commands.waiting.remove(i);
+                eprintln!(
+                    "giving up on {}{} after {} attempts",
+                    w.key.0 as char, w.key.1, w.attempts
+                );
+                commands.orphan(w.id);
+                w.resolve(CommandStatus::TimedOut, Vec::new());
+                continue;
+            }
+            w.attempts += 1;
+            w.schedule(now);
+            let sent = Sent::new(&w.code, w.id, w.due);
+            self.tx.lock().unwrap().send(w.code.clone()).unwrap();
+            commands.sent.push(sent);
+            i += 1;
         }
     }
 
     pub fn earliest_due(&self) -> Option<Instant> {
-        self.waiting.lock().unwrap().iter().map(|v| v.due).min()
+        let commands = self.commands.lock().unwrap();
+        commands.waiting.iter().map(|v| v.due).min()
     }
 }
 
-pub fn request_all_sensors_code() -> CodeSend {
-    CodeSend {
-        letter: b'M',
-        number: 1,
-        params: vec![],
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{
+            tests::{ack, code, encode, report, value_report},
+            StationCommand,
+        },
+        transport::MemoryTransport,
+    };
+
+    #[derive(Debug)]
+    enum Received {
+        Code(CodeSend),
+        CodeErr(ScodeError),
+        Link(LinkState),
     }
-}
 
-pub fn request_sensor_code(sensor: u8, only_value: bool) -> CodeSend {
-    CodeSend {
-        letter: b'S',
-        number: sensor,
-        params: match only_value {
-            true => vec![ParamSend {
-                letter: b'R',
-                value: ParamValue::str("V"),
-            }],
-            false => vec![],
-        },
+    impl From<CodeSend> for Received {
+        fn from(value: CodeSend) -> Self {
+            Self::Code(value)
+        }
+    }
+    impl From<ScodeError> for Received {
+        fn from(value: ScodeError) -> Self {
+            Self::CodeErr(value)
+        }
+    }
+    impl From<LinkState> for Received {
+        fn from(value: LinkState) -> Self {
+            Self::Link(value)
+        }
     }
-}
 
-pub fn set_clock_code() -> CodeSend {
-    let year = NaiveDate::from_yo_opt(1984, 1).unwrap();
-    let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
-
-    let now = chrono::offset::Local::now();
-    let days = (now.date_naive() - year).num_days();
-    let ms = (now.time() - midnight).num_milliseconds();
-
-    CodeSend {
-        letter: b'M',
-        number: 10,
-        params: vec![
-            ParamSend {
-                letter: b'D',
-                value: (days as i32).into(),
-            },
-            ParamSend {
-                letter: b'T',
-                value: (ms as i32).into(),
-            },
-        ],
+    /// Run a reader over one end of a memory link
+    fn spawn_reader(transport: MemoryTransport) -> (Receiver<Received>, Sender<CodeSend>) {
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let (send_tx, send_rx) = mpsc::channel();
+        let mut transport = Some(transport);
+        let connect = move || match transport.take() {
+            Some(transport) => Ok(Box::new(transport) as Box<dyn Transport>),
+            None => Err(eyre!("the test is over")),
+        };
+        let mut reader = StationReader::new(connect, recv_tx, send_rx);
+        thread::spawn(move || reader.run());
+        (recv_rx, send_tx)
     }
-}
 
-#[allow(dead_code)]
-pub fn reset_code() -> CodeSend {
-    CodeSend {
-        letter: b'M',
-        number: 20,
-        params: vec![],
+    fn recv(rx: &Receiver<Received>) -> Received {
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Received::CodeErr(err)) => panic!("{err}"),
+            Ok(received) => received,
+            Err(_) => panic!("the reader sent nothing"),
+        }
     }
-}
 
-pub fn request_autos_code() -> CodeSend {
-    CodeSend {
-        letter: b'M',
-        number: 102,
-        params: vec![],
+    /// Read `count` codes from the station's end of the link
+    fn read_codes(station: &mut MemoryTransport, count: usize) -> Vec<CodeSend> {
+        let until = Instant::now() + Duration::from_secs(5);
+        station.set_timeout(Duration::from_millis(50)).unwrap();
+        let mut stream = CodeStream::with_capacity(64);
+        let mut codes = Vec::new();
+        let mut buf = [0; 64];
+        while codes.len() < count {
+            assert!(Instant::now() < until, "the station only got {codes:?}");
+            let len = station.read(&mut buf).unwrap();
+            stream.extend(&buf[0..len]);
+            for code in &mut stream {
+                codes.push(CodeSend::from(code.unwrap_or_else(|err| panic!("{err}"))));
+            }
+        }
+        codes
+    }
+
+    /// Dispatch everything the reader receives, resending commands as they fall due
+    fn spawn_handler(rx: Receiver<Received>, commands: &Arc<CommandManager>) {
+        let mut handler = CodeHandler::new();
+        handler.callback(commands.on_command());
+        handler.callback(commands.on_reply());
+        let commands = commands.clone();
+        thread::spawn(move || loop {
+            match rx.recv_timeout(Duration::from_millis(20)) {
+                Ok(Received::Code(code)) => {
+                    handler.code(code);
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            commands.update();
+        });
+    }
+
+    #[test]
+    fn reader_receives_codes() {
+        let (near, mut station) = MemoryTransport::pair();
+        let (rx, _tx) = spawn_reader(near);
+        assert!(matches!(recv(&rx), Received::Link(LinkState::Up)));
+
+        station
+            .write(&encode([report(3, "uv", "index", 2.0), ack(b'S', 3)]))
+            .unwrap();
+        match recv(&rx) {
+            Received::Code(code) => assert_eq!((code.letter, code.number), (b'S', 3)),
+            other => panic!("expected S3, got {other:?}"),
+        }
+        match recv(&rx) {
+            Received::Code(code) => assert_eq!((code.letter, code.number), (b'O', 1)),
+            other => panic!("expected O1, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn reader_reports_a_lost_link() {
+        let (near, station) = MemoryTransport::pair();
+        let (rx, _tx) = spawn_reader(near);
+        assert!(matches!(recv(&rx), Received::Link(LinkState::Up)));
+        drop(station);
+        assert!(matches!(recv(&rx), Received::Link(LinkState::Down(_))));
+    }
+
+    #[test]
+    fn reader_sends_commands() {
+        let (near, mut station) = MemoryTransport::pair();
+        let (_rx, tx) = spawn_reader(near);
+        tx.send(StationCommand::RequestAllSensors.into()).unwrap();
+        tx.send(StationCommand::RequestAutos.into()).unwrap();
+
+        let codes = read_codes(&mut station, 2);
+        let keys: Vec<_> = codes.iter().map(|c| (c.letter, c.number)).collect();
+        assert_eq!(keys, vec![(b'M', 1), (b'M', 102)]);
+    }
+
+    #[test]
+    fn request_over_link() {
+        let (near, mut station) = MemoryTransport::pair();
+        let (rx, tx) = spawn_reader(near);
+        let commands = Arc::new(CommandManager::new(tx));
+        spawn_handler(rx, &commands);
+
+        let pending = commands.request_async(
+            StationCommand::RequestAllSensors.into(),
+            RetryPolicy::new(Duration::from_secs(5)),
+        );
+        let codes = read_codes(&mut station, 1);
+        assert_eq!((codes[0].letter, codes[0].number), (b'M', 1));
+        station
+            .write(&encode([
+                report(0, "wind heading", "deg", 180.0),
+                report(1, "wind speed", "m/s", 3.0),
+                ack(b'M', 1),
+            ]))
+            .unwrap();
+
+        let response = pending.wait().unwrap();
+        assert_eq!(response.key, (b'M', 1));
+        let ids: Vec<_> = response.replies.iter().map(|c| c.number).collect();
+        assert_eq!(ids, vec![0, 1]);
+    }
+
+    #[test]
+    fn request_is_resent_until_answered() {
+        let (near, mut station) = MemoryTransport::pair();
+        let (rx, tx) = spawn_reader(near);
+        let commands = Arc::new(CommandManager::new(tx));
+        spawn_handler(rx, &commands);
+
+        let pending = commands.request_async(
+            StationCommand::RequestAutos.into(),
+            RetryPolicy::new(Duration::from_millis(200)),
+        );
+        // Pretend the first one got lost, and only answer the resend
+        let codes = read_codes(&mut station, 2);
+        assert!(codes.iter().all(|c| (c.letter, c.number) == (b'M', 102)));
+        station
+            .write(&encode([code(b'M', 102, vec![]), ack(b'M', 102)]))
+            .unwrap();
+
+        let response = pending.wait().unwrap();
+        assert_eq!(response.replies.len(), 1);
+    }
+
+    #[test]
+    fn request_times_out() {
+        let (near, _station) = MemoryTransport::pair();
+        let (rx, tx) = spawn_reader(near);
+        let commands = Arc::new(CommandManager::new(tx));
+        spawn_handler(rx, &commands);
+
+        let pending = commands.request_async(
+            StationCommand::RequestAutos.into(),
+            RetryPolicy::new(Duration::from_millis(10)).max_attempts(2),
+        );
+        assert!(pending.wait().is_err());
+    }
+
+    /// A manager fed directly, without a link
+    fn manager() -> (Arc<CommandManager>, CodeHandler, Receiver<CodeSend>) {
+        let (tx, rx) = mpsc::channel();
+        let commands = Arc::new(CommandManager::new(tx));
+        let mut handler = CodeHandler::new();
+        handler.callback(commands.on_reply());
+        handler.callback(commands.on_command());
+        (commands, handler, rx)
+    }
+
+    fn sensor(id: u8, only_value: bool) -> CodeSend {
+        StationCommand::RequestSensor { id, only_value }.into()
+    }
+
+    /// The sensor ids and values of the replies
+    fn reported(pending: PendingResponse) -> Vec<(u8, f32)> {
+        let response = pending.wait().unwrap();
+        let report = |code: &CodeSend| match StationMessage::parse(code) {
+            Ok(StationMessage::SensorReport(report)) => (report.id, report.value),
+            other => panic!("expected a sensor report, got {other:?}"),
+        };
+        response.replies.iter().map(report).collect()
+    }
+
+    #[test]
+    fn concurrent_requests_for_one_sensor() {
+        let (commands, mut handler, _rx) = manager();
+        let first = commands.request_async(sensor(3, false), Duration::from_secs(60));
+        let second = commands.request_async(sensor(3, false), Duration::from_secs(60));
+
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(ack(b'S', 3));
+        handler.code(report(3, "uv", "index", 2.0));
+        handler.code(ack(b'S', 3));
+
+        assert_eq!(reported(first), vec![(3, 1.0)]);
+        assert_eq!(reported(second), vec![(3, 2.0)]);
+    }
+
+    #[test]
+    fn late_answer_to_a_resend_is_swallowed() {
+        let (commands, mut handler, _rx) = manager();
+        let retry = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::new(Duration::from_secs(60))
+        };
+        let first = commands.request_async(sensor(3, false), retry);
+        // Resent before the station got around to answering
+        commands.commands.lock().unwrap().waiting[0].due = Instant::now();
+        commands.update();
+
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(ack(b'S', 3));
+        assert_eq!(reported(first), vec![(3, 1.0)]);
+
+        let second = commands.request_async(sensor(3, false), Duration::from_secs(60));
+        // The answer to the resend
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(ack(b'S', 3));
+        assert!(second.rx.try_recv().is_err());
+
+        handler.code(report(3, "uv", "index", 2.0));
+        handler.code(ack(b'S', 3));
+        assert_eq!(reported(second), vec![(3, 2.0)]);
+    }
+
+    #[test]
+    fn lost_answer_leaves_nothing_behind() {
+        let (commands, mut handler, rx) = manager();
+        let retry = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::new(Duration::from_millis(20))
+        };
+        let first = commands.request_async(sensor(3, false), retry);
+        // The station answered, but the O1 got lost
+        handler.code(report(3, "uv", "index", 1.0));
+        thread::sleep(Duration::from_millis(25));
+        commands.update();
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(ack(b'S', 3));
+        assert_eq!(reported(first), vec![(3, 1.0)]);
+
+        // Long enough for the resend's answer to count as lost
+        thread::sleep(Duration::from_millis(50));
+        let second = commands.request_async(sensor(3, false), Duration::from_secs(60));
+        handler.code(report(3, "uv", "index", 2.0));
+        handler.code(ack(b'S', 3));
+        let result = second.rx.try_recv().expect("the first send was not enough");
+        let response = result.into_response().unwrap();
+        assert_eq!(response.replies.len(), 1);
+        // The first request was resent once, the second never was
+        assert_eq!(rx.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn sensor_request_before_all_sensors() {
+        let (commands, mut handler, _rx) = manager();
+        let one = commands.request_async(sensor(3, false), Duration::from_secs(60));
+        let all = commands.request_async(
+            StationCommand::RequestAllSensors.into(),
+            Duration::from_secs(60),
+        );
+
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(ack(b'S', 3));
+        handler.code(report(0, "temp", "C", 20.0));
+        handler.code(value_report(5, 7.0));
+        handler.code(report(3, "uv", "index", 2.0));
+        handler.code(ack(b'M', 1));
+
+        assert_eq!(reported(one), vec![(3, 1.0)]);
+        assert_eq!(reported(all), vec![(0, 20.0), (3, 2.0)]);
+    }
+
+    #[test]
+    fn all_sensors_before_sensor_request() {
+        let (commands, mut handler, _rx) = manager();
+        let all = commands.request_async(
+            StationCommand::RequestAllSensors.into(),
+            Duration::from_secs(60),
+        );
+        let one = commands.request_async(sensor(3, false), Duration::from_secs(60));
+        let value = commands.request_async(sensor(4, true), Duration::from_secs(60));
+
+        handler.code(report(0, "temp", "C", 20.0));
+        handler.code(report(3, "uv", "index", 1.0));
+        handler.code(value_report(5, 7.0));
+        handler.code(report(4, "rain", "mm", 0.5));
+        handler.code(ack(b'M', 1));
+        handler.code(report(3, "uv", "index", 2.0));
+        handler.code(ack(b'S', 3));
+        handler.code(value_report(4, 0.75));
+        handler.code(ack(b'S', 4));
+
+        assert_eq!(reported(all), vec![(0, 20.0), (3, 1.0), (4, 0.5)]);
+        assert_eq!(reported(one), vec![(3, 2.0)]);
+        assert_eq!(reported(value), vec![(4, 0.75)]);
     }
 }
@@ -0,0 +1,275 @@
+use color_eyre::{eyre::eyre, Result};
+use ordoo::or_do;
+#[cfg(test)]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use serialport::SerialPort;
+
+use crate::conf::{Parity, SerialConf, TransportKind};
+
+/// A byte stream to the station
+pub trait Transport: Send {
+    /// Read any available bytes into `buf`
+    ///
+    /// Waits at most the configured timeout for data to arrive, returning 0
+    /// if nothing was received.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    /// Write bytes to the station, returning how many were written
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    /// Set how long `read` may wait for data
+    ///
+    /// A timeout of zero makes reads non-blocking.
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Check whether the underlying device is still present
+    ///
+    /// Some devices, like unplugged USB adapters, never report an error and
+    /// simply stop producing data.
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+/// Open the transport described by the serial configuration
+pub fn open(conf: &SerialConf) -> Result<Box<dyn Transport>> {
+    Ok(match conf.transport {
+        #[cfg(feature = "uart")]
+        TransportKind::Uart => Box::new(rppal::uart::Uart::with_path(
+            &conf.path,
+            conf.baudrate,
+            conf.parity.into(),
+            conf.databits,
+            conf.stopbits,
+        )?),
+        #[cfg(not(feature = "uart"))]
+        TransportKind::Uart => {
+            return Err(eyre!(
+                "uart transport is unavailable, station-comms was built without the `uart` feature"
+            ))
+        }
+        TransportKind::Serial => Box::new(SerialTransport::open(conf)?),
+        TransportKind::Tcp => {
+            let address = or_do!(
+                conf.address.as_deref(),
+                return Err(eyre!("the tcp transport requires `serial.address`"))
+            );
+            Box::new(TcpTransport::connect(address)?)
+        }
+    })
+}
+
+#[cfg(feature = "uart")]
+impl Transport for rppal::uart::Uart {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(rppal::uart::Uart::read(self, buf)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(rppal::uart::Uart::write(self, buf)?)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        Ok(self.set_read_mode(0, timeout)?)
+    }
+}
+
+/// A generic serial device, such as a USB adapter or a pseudo-terminal
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    path: PathBuf,
+}
+
+impl SerialTransport {
+    pub fn open(conf: &SerialConf) -> Result<Self> {
+        let parity = match conf.parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Even => serialport::Parity::Even,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Mark | Parity::Space => {
+                return Err(eyre!(
+                    "{:?} parity is not supported by the serial transport",
+                    conf.parity
+                ))
+            }
+        };
+        let databits = match conf.databits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            8 => serialport::DataBits::Eight,
+            v => return Err(eyre!("unsupported number of data bits: {v}")),
+        };
+        let stopbits = match conf.stopbits {
+            1 => serialport::StopBits::One,
+            2 => serialport::StopBits::Two,
+            v => return Err(eyre!("unsupported number of stop bits: {v}")),
+        };
+
+        let port = serialport::new(conf.path.to_string_lossy(), conf.baudrate)
+            .parity(parity)
+            .data_bits(databits)
+            .stop_bits(stopbits)
+            .timeout(Duration::ZERO)
+            .open()?;
+        Ok(Self {
+            port,
+            path: conf.path.clone(),
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.port.read(buf) {
+            Ok(len) => Ok(len),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(self.port.write(buf)?)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        Ok(self.port.set_timeout(timeout)?)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.path.exists()
+    }
+}
+
+/// A station reachable over a TCP socket
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(address: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(eyre!("connection closed by the station")),
+            Ok(len) => Ok(len),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.stream.write(buf) {
+            Ok(len) => Ok(len),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        // A zero read timeout is rejected by std, so use non-blocking mode instead
+        if timeout.is_zero() {
+            self.stream.set_nonblocking(true)?;
+        } else {
+            self.stream.set_nonblocking(false)?;
+            self.stream.set_read_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+}
+
+/// One end of an in-memory connection
+///
+/// Drives a `StationReader` from tests without any hardware.
+#[cfg(test)]
+pub struct MemoryTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    timeout: Duration,
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    /// Create two connected transports
+    ///
+    /// Bytes written to one end can be read from the other.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        (
+            Self {
+                tx: a_tx,
+                rx: b_rx,
+                pending: Vec::new(),
+                timeout: Duration::ZERO,
+            },
+            Self {
+                tx: b_tx,
+                rx: a_rx,
+                pending: Vec::new(),
+                timeout: Duration::ZERO,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pending.is_empty() {
+            let recv = if self.timeout.is_zero() {
+                self.rx.try_recv().map_err(|e| match e {
+                    TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                })
+            } else {
+                self.rx.recv_timeout(self.timeout)
+            };
+            match recv {
+                Ok(bytes) => self.pending = bytes,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(eyre!("memory transport disconnected"))
+                }
+            }
+        }
+        let len = self.pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| eyre!("memory transport disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use serde::Deserialize;
+
+/// What a unit measures, units can only be converted within a quantity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Speed,
+    Pressure,
+    Length,
+    Altitude,
+    RainRate,
+}
+
+/// A unit that we know how to convert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    MetersPerSecond,
+    KilometersPerHour,
+    MilesPerHour,
+    Knots,
+    Hectopascal,
+    Pascal,
+    InchesOfMercury,
+    MillimetersOfMercury,
+    Millimeters,
+    Inches,
+    Meters,
+    Feet,
+    MillimetersPerHour,
+    InchesPerHour,
+}
+
+impl Unit {
+    /// Parse the unit of a station sensor
+    ///
+    /// The firmware's units are free-form, so this accepts the common
+    /// spellings of each unit.
+    pub fn parse(unit: &str) -> Option<Self> {
+        let unit = unit.trim().to_lowercase();
+        let unit = unit.trim_start_matches('°').trim_start_matches("deg ");
+        Some(match unit {
+            "c" | "degc" | "celsius" => Self::Celsius,
+            "f" | "degf" | "fahrenheit" => Self::Fahrenheit,
+            "k" | "kelvin" => Self::Kelvin,
+            "m/s" | "mps" => Self::MetersPerSecond,
+            "km/h" | "kmh" | "kph" => Self::KilometersPerHour,
+            "mph" | "mi/h" => Self::MilesPerHour,
+            "kn" | "kt" | "kts" | "knots" => Self::Knots,
+            "hpa" | "mbar" | "mb" => Self::Hectopascal,
+            "pa" => Self::Pascal,
+            "inhg" | "in hg" => Self::InchesOfMercury,
+            "mmhg" | "mm hg" => Self::MillimetersOfMercury,
+            "mm" => Self::Millimeters,
+            "in" | "inch" | "inches" => Self::Inches,
+            "m" | "meters" => Self::Meters,
+            "ft" | "feet" => Self::Feet,
+            "mm/h" | "mm/hr" => Self::MillimetersPerHour,
+            "in/h" | "in/hr" => Self::InchesPerHour,
+            _ => return None,
+        })
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Fahrenheit => "F",
+            Self::Kelvin => "K",
+            Self::MetersPerSecond => "m/s",
+            Self::KilometersPerHour => "km/h",
+            Self::MilesPerHour => "mph",
+            Self::Knots => "kn",
+            Self::Hectopascal => "hPa",
+            Self::Pascal => "Pa",
+            Self::InchesOfMercury => "inHg",
+            Self::MillimetersOfMercury => "mmHg",
+            Self::Millimeters => "mm",
+            Self::Inches => "in",
+            Self::Meters => "m",
+            Self::Feet => "ft",
+            Self::MillimetersPerHour => "mm/h",
+            Self::InchesPerHour => "in/h",
+        }
+    }
+
+    pub fn quantity(self) -> Quantity {
+        match self {
+            Self::Celsius | Self::Fahrenheit | Self::Kelvin => Quantity::Temperature,
+            Self::MetersPerSecond | Self::KilometersPerHour | Self::MilesPerHour | Self::Knots => {
+                Quantity::Speed
+            }
+            Self::Hectopascal
+            | Self::Pascal
+            | Self::InchesOfMercury
+            | Self::MillimetersOfMercury => Quantity::Pressure,
+            Self::Millimeters | Self::Inches => Quantity::Length,
+            Self::Meters | Self::Feet => Quantity::Altitude,
+            Self::MillimetersPerHour | Self::InchesPerHour => Quantity::RainRate,
+        }
+    }
+
+    /// Convert to the quantity's base unit: C, m/s, hPa, mm, m or mm/h
+    fn to_base(self, value: f32) -> f32 {
+        match self {
+            Self::Celsius
+            | Self::MetersPerSecond
+            | Self::Hectopascal
+            | Self::Millimeters
+            | Self::Meters
+            | Self::MillimetersPerHour => value,
+            Self::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Self::Kelvin => value - 273.15,
+            Self::KilometersPerHour => value / 3.6,
+            Self::MilesPerHour => value * 0.44704,
+            Self::Knots => value * 1852.0 / 3600.0,
+            Self::Pascal => value / 100.0,
+            Self::InchesOfMercury => value * 33.863_89,
+            Self::MillimetersOfMercury => value * 1.333_224,
+            Self::Inches | Self::InchesPerHour => value * 25.4,
+            Self::Feet => value * 0.3048,
+        }
+    }
+
+    /// Convert from the quantity's base unit
+    fn from_base(self, value: f32) -> f32 {
+        match self {
+            Self::Celsius
+            | Self::MetersPerSecond
+            | Self::Hectopascal
+            | Self::Millimeters
+            | Self::Meters
+            | Self::MillimetersPerHour => value,
+            Self::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            Self::Kelvin => value + 273.15,
+            Self::KilometersPerHour => value * 3.6,
+            Self::MilesPerHour => value / 0.44704,
+            Self::Knots => value * 3600.0 / 1852.0,
+            Self::Pascal => value * 100.0,
+            Self::InchesOfMercury => value / 33.863_89,
+            Self::MillimetersOfMercury => value / 1.333_224,
+            Self::Inches | Self::InchesPerHour => value / 25.4,
+            Self::Feet => value / 0.3048,
+        }
+    }
+
+    /// Convert a value to another unit of the same quantity
+    pub fn convert(self, value: f32, to: Unit) -> Option<f32> {
+        if self.quantity() != to.quantity() {
+            return None;
+        }
+        if self == to {
+            return Some(value);
+        }
+        Some(to.from_base(self.to_base(value)))
+    }
+}
+
+/// The units to publish in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    /// Whatever units the station reports in
+    Station,
+    /// C, m/s, hPa, mm and m
+    Metric,
+    /// F, mph, inHg, in and ft
+    Imperial,
+    /// C, kn, hPa, mm and ft
+    Nautical,
+}
+
+impl Default for UnitSystem {
+    fn default() -> Self {
+        Self::Station
+    }
+}
+
+impl UnitSystem {
+    /// The unit this system uses for a quantity
+    pub fn unit(self, quantity: Quantity) -> Option<Unit> {
+        Some(match (self, quantity) {
+            (Self::Station, _) => return None,
+            (Self::Imperial, Quantity::Temperature) => Unit::Fahrenheit,
+            (_, Quantity::Temperature) => Unit::Celsius,
+            (Self::Metric, Quantity::Speed) => Unit::MetersPerSecond,
+            (Self::Imperial, Quantity::Speed) => Unit::MilesPerHour,
+            (Self::Nautical, Quantity::Speed) => Unit::Knots,
+            (Self::Imperial, Quantity::Pressure) => Unit::InchesOfMercury,
+            (_, Quantity::Pressure) => Unit::Hectopascal,
+            (Self::Imperial, Quantity::Length) => Unit::Inches,
+            (_, Quantity::Length) => Unit::Millimeters,
+            (Self::Metric, Quantity::Altitude) => Unit::Meters,
+            (_, Quantity::Altitude) => Unit::Feet,
+            (Self::Imperial, Quantity::RainRate) => Unit::InchesPerHour,
+            (_, Quantity::RainRate) => Unit::MillimetersPerHour,
+        })
+    }
+
+    /// Convert a value from a station unit into this system
+    ///
+    /// Units that can't be parsed are passed through unchanged.
+    pub fn convert<'a>(self, value: f32, unit: &'a str) -> (f32, &'a str) {
+        let from = match Unit::parse(unit) {
+            Some(from) => from,
+            None => return (value, unit),
+        };
+        match self.unit(from.quantity()) {
+            Some(to) => (from.convert(value, to).unwrap_or(value), to.symbol()),
+            None => (value, unit),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    conf::{QualityConf, SensorsConf, WindConf},
+    history::{History, Sample},
+    report::{self, ReportBuilder},
+    sensor::{Quality, Sensor, Sensors},
+    units::Unit,
+};
+
+const COMPASS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+
+/// The 16 point compass label of a heading in degrees
+pub fn compass(heading: f32) -> &'static str {
+    COMPASS[(heading.rem_euclid(360.0) / 22.5).round() as usize % 16]
+}
+
+/// Upper bounds of each Beaufort number in m/s
+const BEAUFORT: [f32; 12] = [
+    0.5, 1.5, 3.3, 5.5, 7.9, 10.7, 13.8, 17.1, 20.7, 24.4, 28.4, 32.6,
+];
+
+/// The Beaufort number of a wind speed in m/s
+pub fn beaufort(speed: f32) -> u8 {
+    BEAUFORT.iter().take_while(|max| speed >= **max).count() as u8
+}
+
+/// Wind over a window of samples
+#[derive(Debug, Clone, Copy)]
+pub struct WindStats {
+    /// Magnitude of the vector mean of the wind
+    pub speed: Option<f32>,
+    /// Speed weighted vector mean of the heading, if the wind went anywhere
+    pub heading: Option<f32>,
+    pub gust: Sample,
+    pub gust_heading: Option<f32>,
+    pub lull: f32,
+    /// The latest sample in the window
+    pub measured: Instant,
+}
+
+impl WindStats {
+    /// Average the wind over the last `window`
+    ///
+    /// Each speed is paired with the heading sampled closest to it, and the
+    /// wind is averaged as a vector, so that 350 and 10 degrees average to
+    /// north. Opposing winds cancel out, which makes the average speed lower
+    /// than the scalar mean when the heading varies.
+    pub fn new(speed: &History, heading: &History, window: Duration) -> Option<Self> {
+        let mut samples = speed.since(window);
+        let first = *samples.next()?;
+        let mut count = 0;
+        let (mut east, mut north) = (0.0, 0.0);
+        let mut gust = first;
+        let mut lull = first.value;
+        let mut measured = first.time;
+
+        for sample in std::iter::once(&first).chain(samples) {
+            if sample.value > gust.value {
+                gust = *sample;
+            }
+            lull = lull.min(sample.value);
+            measured = sample.time;
+            if let Some(h) = heading.nearest(sample.time) {
+                let (sin, cos) = h.value.to_radians().sin_cos();
+                east += sample.value * sin;
+                north += sample.value * cos;
+                count += 1;
+            }
+        }
+
+        let speed = (count > 0).then(|| f32::hypot(east, north) / count as f32);
+        let heading_mean = if f32::hypot(east, north) > f32::EPSILON {
+            Some(f32::atan2(east, north).to_degrees().rem_euclid(360.0))
+        } else {
+            None
+        };
+        Some(Self {
+            speed,
+            heading: heading_mean,
+            gust,
+            gust_heading: heading.nearest(gust.time).map(|h| h.value),
+            lull,
+            measured,
+        })
+    }
+}
+
+/// The first sensor behind a key, and its history
+fn source<'s>(
+    sensors: &'s Sensors,
+    conf: &SensorsConf,
+    key: &str,
+) -> Option<(&'s Sensor, &'s History)> {
+    let sensor = report::lookup(sensors, conf, key).into_iter().next()?;
+    Some((sensor, sensors.history(&sensor.name)?))
+}
+
+/// Fill in the wind keys the station doesn't have sensors for
+///
+/// Also adds the Beaufort number and compass label of the current wind.
+pub fn publish(
+    builder: &mut ReportBuilder,
+    conf: &WindConf,
+    sensors: &Sensors,
+    sensors_conf: &SensorsConf,
+    quality: &QualityConf,
+) {
+    if !conf.enabled {
+        return;
+    }
+    let speed = source(sensors, sensors_conf, "windspd");
+    let heading = source(sensors, sensors_conf, "winddir");
+
+    if let Some((s, _)) = speed {
+        let unit = Unit::parse(&s.unit);
+        if let Some(mps) = unit.and_then(|u| u.convert(s.value, Unit::MetersPerSecond)) {
+            let b = beaufort(mps) as f32;
+            builder.value(
+                "windspd-beaufort",
+                "bft",
+                b,
+                s.last_update,
+                s.quality(quality),
+            );
+        }
+    }
+    if let Some((h, _)) = heading {
+        if h.quality(quality) == Quality::Fresh {
+            builder.text("winddir-compass", compass(h.value));
+        }
+    }
+
+    let ((s, speeds), (h, headings)) = match speed.zip(heading) {
+        Some(sources) => sources,
+        None => return,
+    };
+    let q = s.quality(quality).max(h.quality(quality));
+    for minutes in &conf.windows {
+        let window = Duration::from_secs(*minutes as u64 * 60);
+        let stats = match WindStats::new(speeds, headings, window) {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let m = minutes;
+        let keys = [
+            (
+                format!("windspd-avg{m}m"),
+                &s.unit,
+                stats.speed,
+                stats.measured,
+            ),
+            (
+                format!("winddir-avg{m}m"),
+                &h.unit,
+                stats.heading,
+                stats.measured,
+            ),
+            (
+                format!("windgustspd-{m}m"),
+                &s.unit,
+                Some(stats.gust.value),
+                stats.gust.time,
+            ),
+            (
+                format!("windgustdir-{m}m"),
+                &h.unit,
+                stats.gust_heading,
+                stats.gust.time,
+            ),
+            (
+                format!("windlull-{m}m"),
+                &s.unit,
+                Some(stats.lull),
+                stats.measured,
+            ),
+        ];
+        for (key, unit, value, measured) in keys {
+            // The station's own averages take priority
+            if let Some(value) = value.filter(|_| builder.is_missing(&key)) {
+                builder.value(&key, unit, value, measured, q);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Histories of speeds and headings taken a second apart, oldest first
+    fn histories(samples: &[(f32, Option<f32>)]) -> (History, History) {
+        let mut speed = History::new(Duration::from_secs(600), 64);
+        let mut heading = History::new(Duration::from_secs(600), 64);
+        let now = Instant::now();
+        for (i, (s, h)) in samples.iter().enumerate() {
+            let time = now - Duration::from_secs((samples.len() - i) as u64);
+            speed.push(time, *s);
+            if let Some(h) = h {
+                heading.push(time, *h);
+            }
+        }
+        (speed, heading)
+    }
+
+    fn stats(samples: &[(f32, Option<f32>)]) -> WindStats {
+        let (speed, heading) = histories(samples);
+        WindStats::new(&speed, &heading, Duration::from_secs(600)).unwrap()
+    }
+
+    #[test]
+    fn headings_average_around_north() {
+        let stats = stats(&[(5.0, Some(350.0)), (5.0, Some(10.0))]);
+        let heading = stats.heading.unwrap();
+        assert!(heading < 0.01 || heading > 359.99, "{heading}");
+        let speed = stats.speed.unwrap();
+        assert!((speed - 5.0 * 10f32.to_radians().cos()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn opposing_winds_cancel() {
+        let stats = stats(&[(4.0, Some(90.0)), (2.0, Some(270.0))]);
+        let speed = stats.speed.unwrap();
+        assert!((speed - 1.0).abs() < 1e-4, "{speed}");
+        assert!((stats.heading.unwrap() - 90.0).abs() < 0.01);
+        // Gusts and lulls don't care about the heading
+        assert_eq!(stats.gust.value, 4.0);
+        assert_eq!(stats.gust_heading, Some(90.0));
+        assert_eq!(stats.lull, 2.0);
+    }
+
+    #[test]
+    fn no_headings() {
+        let stats = stats(&[(4.0, None), (2.0, None)]);
+        assert_eq!(stats.speed, None);
+        assert_eq!(stats.heading, None);
+        assert_eq!(stats.gust.value, 4.0);
+        assert_eq!(stats.lull, 2.0);
+    }
+
+    #[test]
+    fn empty_window() {
+        let (speed, heading) = histories(&[]);
+        assert!(WindStats::new(&speed, &heading, Duration::from_secs(600)).is_none());
+    }
+
+    #[test]
+    fn beaufort_boundaries() {
+        assert_eq!(beaufort(0.0), 0);
+        assert_eq!(beaufort(0.49), 0);
+        assert_eq!(beaufort(0.5), 1);
+        assert_eq!(beaufort(10.69), 5);
+        assert_eq!(beaufort(10.7), 6);
+        assert_eq!(beaufort(32.59), 11);
+        assert_eq!(beaufort(32.6), 12);
+        assert_eq!(beaufort(100.0), 12);
+    }
+
+    #[test]
+    fn compass_boundaries() {
+        assert_eq!(compass(0.0), "N");
+        assert_eq!(compass(11.2), "N");
+        assert_eq!(compass(11.25), "NNE");
+        assert_eq!(compass(348.7), "NNW");
+        assert_eq!(compass(348.75), "N");
+        assert_eq!(compass(360.0), "N");
+        assert_eq!(compass(-90.0), "W");
+        assert_eq!(compass(180.0), "S");
+    }
+}